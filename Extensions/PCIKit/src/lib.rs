@@ -7,7 +7,8 @@
 use num_enum::IntoPrimitive;
 use serde::{Deserialize, Serialize};
 #[cfg(feature = "ext")]
-use skykit::msg::Message;
+use skykit::rpc::RPCClient;
+use skykit::{rpc::RPCService, SkyError};
 
 #[macro_use]
 extern crate bitfield_struct;
@@ -92,10 +93,36 @@ pub enum PCIRequest {
     Write32(PCIAddress, u8, u32),
 }
 
-#[cfg(feature = "ext")]
-impl PCIRequest {
-    pub unsafe fn send(self, pid: u64) {
-        Message::new(pid, postcard::to_allocvec(&self).unwrap().leak()).send();
+#[derive(Debug, Serialize, Deserialize)]
+pub enum PCIResponse {
+    Byte(u8),
+    Word(u16),
+    DWord(u32),
+    Written,
+}
+
+pub struct PCIService;
+
+impl PCIService {
+    pub const NAME: &'static str = "org.ChefKiss.PCIKit.Config";
+}
+
+impl RPCService for PCIService {
+    type Request = PCIRequest;
+    type Response = PCIResponse;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PCIError {
+    /// The request could not be sent to the service.
+    Sky(SkyError),
+    /// The service answered with a response of the wrong kind.
+    UnexpectedResponse,
+}
+
+impl From<SkyError> for PCIError {
+    fn from(v: SkyError) -> Self {
+        Self::Sky(v)
     }
 }
 
@@ -115,38 +142,69 @@ impl PCIDevice {
 
 #[cfg(feature = "ext")]
 impl PCIDevice {
-    #[must_use]
-    pub unsafe fn is_multifunction(&self) -> bool {
-        (self.cfg_read8::<_, u8>(PCICfgOffset::HeaderType) & 0x80) != 0
+    #[inline]
+    const fn client(&self) -> RPCClient<PCIService> {
+        RPCClient::new(self.pid)
     }
 
-    #[must_use]
-    pub unsafe fn cfg_read8<A: Into<u8>, R: From<u8>>(&self, off: A) -> R {
-        PCIRequest::Read8(self.addr, off.into()).send(self.pid);
-        Message::recv().data[0].into()
+    pub unsafe fn is_multifunction(&self) -> Result<bool, PCIError> {
+        Ok((self.cfg_read8::<_, u8>(PCICfgOffset::HeaderType)? & 0x80) != 0)
     }
 
-    #[must_use]
-    pub unsafe fn cfg_read16<A: Into<u8>, R: From<u16>>(&self, off: A) -> R {
-        PCIRequest::Read16(self.addr, off.into()).send(self.pid);
-        u16::from_le_bytes(Message::recv().data.try_into().unwrap()).into()
+    pub unsafe fn cfg_read8<A: Into<u8>, R: From<u8>>(&self, off: A) -> Result<R, PCIError> {
+        let PCIResponse::Byte(v) = self
+            .client()
+            .call(PCIRequest::Read8(self.addr, off.into()))?
+        else {
+            return Err(PCIError::UnexpectedResponse);
+        };
+        Ok(v.into())
     }
 
-    #[must_use]
-    pub unsafe fn cfg_read32<A: Into<u8>, R: From<u32>>(&self, off: A) -> R {
-        PCIRequest::Read32(self.addr, off.into()).send(self.pid);
-        u32::from_le_bytes(Message::recv().data.try_into().unwrap()).into()
+    pub unsafe fn cfg_read16<A: Into<u8>, R: From<u16>>(&self, off: A) -> Result<R, PCIError> {
+        let PCIResponse::Word(v) = self
+            .client()
+            .call(PCIRequest::Read16(self.addr, off.into()))?
+        else {
+            return Err(PCIError::UnexpectedResponse);
+        };
+        Ok(v.into())
+    }
+
+    pub unsafe fn cfg_read32<A: Into<u8>, R: From<u32>>(&self, off: A) -> Result<R, PCIError> {
+        let PCIResponse::DWord(v) = self
+            .client()
+            .call(PCIRequest::Read32(self.addr, off.into()))?
+        else {
+            return Err(PCIError::UnexpectedResponse);
+        };
+        Ok(v.into())
     }
 
-    pub unsafe fn cfg_write8<A: Into<u8>, R: Into<u8>>(&self, off: A, value: R) {
-        PCIRequest::Write8(self.addr, off.into(), value.into()).send(self.pid);
+    pub unsafe fn cfg_write8<A: Into<u8>, R: Into<u8>>(
+        &self,
+        off: A,
+        value: R,
+    ) -> Result<(), SkyError> {
+        self.client()
+            .notify(PCIRequest::Write8(self.addr, off.into(), value.into()))
     }
 
-    pub unsafe fn cfg_write16<A: Into<u8>, R: Into<u16>>(&self, off: A, value: R) {
-        PCIRequest::Write16(self.addr, off.into(), value.into()).send(self.pid);
+    pub unsafe fn cfg_write16<A: Into<u8>, R: Into<u16>>(
+        &self,
+        off: A,
+        value: R,
+    ) -> Result<(), SkyError> {
+        self.client()
+            .notify(PCIRequest::Write16(self.addr, off.into(), value.into()))
     }
 
-    pub unsafe fn cfg_write32<A: Into<u8>, R: Into<u32>>(&self, off: A, value: R) {
-        PCIRequest::Write32(self.addr, off.into(), value.into()).send(self.pid);
+    pub unsafe fn cfg_write32<A: Into<u8>, R: Into<u32>>(
+        &self,
+        off: A,
+        value: R,
+    ) -> Result<(), SkyError> {
+        self.client()
+            .notify(PCIRequest::Write32(self.addr, off.into(), value.into()))
     }
 }
@@ -6,7 +6,6 @@
 
 // #[macro_use]
 // extern crate log;
-#[macro_use]
 extern crate alloc;
 #[macro_use]
 extern crate itertools;
@@ -14,8 +13,14 @@ extern crate itertools;
 use alloc::{boxed::Box, string::String};
 
 use hashbrown::HashMap;
-use pcikit::{PCIAddress, PCICfgOffset, PCIRequest};
-use skykit::{msg::Message, osdtentry::OSDTEntry, osvalue::OSValue, userspace::port::Port};
+use pcikit::{PCIAddress, PCICfgOffset, PCIRequest, PCIResponse, PCIService};
+use skykit::{
+    osdtentry::OSDTEntry,
+    osvalue::OSValue,
+    rpc::RPCServer,
+    service,
+    userspace::port::{self, Port},
+};
 
 trait PCIControllerIO: Sync {
     unsafe fn read8(&self, addr: PCIAddress, off: u8) -> u8;
@@ -118,6 +123,7 @@ impl PCIControllerIO for PCIPortIO {
 #[no_mangle]
 extern "C" fn _start(instance: OSDTEntry) -> ! {
     skykit::userspace::logger::init();
+    unsafe { port::grant_direct(0xCF8, 0xCFF).unwrap() }
 
     let controller = Box::new(PCIController);
     for (bus, slot) in iproduct!(0..=255, 0..32) {
@@ -143,11 +149,11 @@ extern "C" fn _start(instance: OSDTEntry) -> ! {
                 ("Function".into(), func.into()),
             ]);
 
-            let ent = instance.new_child(None);
-            ent.set_property("VendorID", vendor_id.into());
-            ent.set_property("DeviceID", device_id.into());
-            ent.set_property("ClassCode", class_code.into());
-            ent.set_property("Address", addr.into());
+            let ent = instance.new_child(None).unwrap();
+            ent.set_property("VendorID", vendor_id.into()).unwrap();
+            ent.set_property("DeviceID", device_id.into()).unwrap();
+            ent.set_property("ClassCode", class_code.into()).unwrap();
+            ent.set_property("Address", addr.into()).unwrap();
 
             if !multifunction {
                 break;
@@ -155,34 +161,24 @@ extern "C" fn _start(instance: OSDTEntry) -> ! {
         }
     }
 
-    loop {
-        let msg = unsafe { Message::recv() };
-        if msg.pid == 0 {
-            continue;
-        }
-
-        let Ok(req) = postcard::from_bytes::<PCIRequest>(msg.data) else {
-            continue;
-        };
-        let data = match req {
-            PCIRequest::Read8(addr, off) => vec![controller.read8(addr, off)],
-            PCIRequest::Read16(addr, off) => controller.read16(addr, off).to_le_bytes().to_vec(),
-            PCIRequest::Read32(addr, off) => controller.read32(addr, off).to_le_bytes().to_vec(),
+    unsafe {
+        service::register(PCIService::NAME).unwrap();
+        RPCServer::<PCIService>::new().serve(|_, req| match req {
+            PCIRequest::Read8(addr, off) => PCIResponse::Byte(controller.read8(addr, off)),
+            PCIRequest::Read16(addr, off) => PCIResponse::Word(controller.read16(addr, off)),
+            PCIRequest::Read32(addr, off) => PCIResponse::DWord(controller.read32(addr, off)),
             PCIRequest::Write8(addr, off, value) => {
                 controller.write8(addr, off, value);
-                continue;
+                PCIResponse::Written
             }
             PCIRequest::Write16(addr, off, value) => {
                 controller.write16(addr, off, value);
-                continue;
+                PCIResponse::Written
             }
             PCIRequest::Write32(addr, off, value) => {
                 controller.write32(addr, off, value);
-                continue;
+                PCIResponse::Written
             }
-        };
-        unsafe {
-            Message::new(msg.pid, data.leak()).send();
-        }
+        })
     }
 }
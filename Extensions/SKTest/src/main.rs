@@ -12,16 +12,21 @@ extern crate alloc;
 extern crate bitfield_struct;
 
 use alloc::string::String;
-use core::fmt::Write;
+use core::{fmt::Write, time::Duration};
 
 use num_enum::IntoPrimitive;
 use serde::{Deserialize, Serialize};
 use skykit::{
     msg::Message,
-    osdtentry::{OSDTEntry, OSDTENTRY_NAME_KEY, SKEXT_PROC_KEY},
+    osdtentry::{OSDTEntry, OSDTENTRY_NAME_KEY},
     osvalue::OSValue,
+    priority, service,
     syscall::SystemCall,
-    userspace::{logger::KWriter, port::Port},
+    userspace::{
+        logger::KWriter,
+        port::{self, Port},
+        thread, time,
+    },
 };
 
 #[derive(IntoPrimitive)]
@@ -56,6 +61,19 @@ enum Ps2Event {
     Other(u8),
 }
 
+const PS2_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Polls `cond` until it holds, giving up after [`PS2_TIMEOUT`].
+fn poll_until(mut cond: impl FnMut() -> bool) -> bool {
+    let deadline = time::deadline_after(PS2_TIMEOUT);
+    while !cond() {
+        if time::now_ns() >= deadline {
+            return false;
+        }
+    }
+    true
+}
+
 struct PS2Ctl {
     data_port: Port<u8, u8>,
     sts_or_cmd_reg: Port<u8, u8>,
@@ -82,22 +100,25 @@ impl PS2Ctl {
     }
 
     #[inline]
-    fn send_cmd(&self, cmd: PS2CtlCmd, wait_for_ack: bool) {
+    fn send_cmd(&self, cmd: PS2CtlCmd, wait_for_ack: bool) -> bool {
+        unsafe { self.sts_or_cmd_reg.write(cmd.into()) }
+        !wait_for_ack || poll_until(|| unsafe { self.data_port.read() } == 0xFA)
+    }
+
+    pub fn init(&self) -> bool {
         unsafe {
-            self.sts_or_cmd_reg.write(cmd.into());
-            if wait_for_ack {
-                while self.data_port.read() != 0xFA {}
-            }
+            port::grant_direct(0x60, 0x60).unwrap();
+            port::grant_direct(0x64, 0x64).unwrap();
         }
-    }
 
-    pub fn init(&self) {
         while self.output_full() {
             let _ = unsafe { self.data_port.read() };
         }
 
         self.send_cmd(PS2CtlCmd::ReadControllerCfg, false);
-        while !self.output_full() {}
+        if !poll_until(|| self.output_full()) {
+            return false;
+        }
 
         let cfg = unsafe {
             Ps2Cfg::from(self.data_port.read())
@@ -105,10 +126,13 @@ impl PS2Ctl {
                 .with_port2_intr(false)
                 .with_port1_translation(true)
         };
-        unsafe { SystemCall::register_irq_handler(1) }
+        unsafe {
+            SystemCall::register_irq_handler(1).unwrap();
+            thread::set_priority(0, priority::REALTIME_MIN).unwrap();
+        }
         self.send_cmd(PS2CtlCmd::WriteControllerCfg, false);
         unsafe { self.data_port.write(cfg.into()) }
-        while self.input_full() {}
+        poll_until(|| !self.input_full())
     }
 }
 
@@ -116,7 +140,7 @@ fn print_ent(ent: OSDTEntry, ident: usize) {
     let spacing = " ".repeat(ident);
 
     let id: u64 = ent.into();
-    let props = ent.properties();
+    let props = ent.properties().unwrap();
     writeln!(
         KWriter,
         "{spacing}+ {} <{}>",
@@ -133,24 +157,23 @@ fn print_ent(ent: OSDTEntry, ident: usize) {
         writeln!(KWriter, "{spacing}|- {k}: {v:X?}").unwrap();
     }
 
-    for child in ent.children() {
+    for child in ent.children().unwrap() {
         print_ent(child, ident + 2);
     }
 }
 
 #[no_mangle]
-extern "C" fn _start(instance: OSDTEntry) -> ! {
+extern "C" fn _start(_instance: OSDTEntry) -> ! {
     skykit::userspace::logger::init();
 
     let this = PS2Ctl::new();
-    this.init();
+    if !this.init() {
+        writeln!(KWriter, "PS/2 controller did not respond").unwrap();
+    }
     let mut s = String::new();
     write!(KWriter, "> ").unwrap();
     loop {
-        let msg = unsafe { Message::recv() };
-        if msg.pid != 0 {
-            continue;
-        }
+        let _irq = unsafe { Message::recv_from(0) };
 
         while this.output_full() {
             let event = match unsafe { this.data_port.read() } {
@@ -185,19 +208,15 @@ extern "C" fn _start(instance: OSDTEntry) -> ! {
 
             match s.as_str() {
                 "osdt" => print_ent(OSDTEntry::default(), 0),
-                "msgparent" => {
-                    let pid: u64 = instance
-                        .parent()
-                        .unwrap()
-                        .parent()
-                        .unwrap()
-                        .get_property(SKEXT_PROC_KEY)
-                        .unwrap()
-                        .try_into()
-                        .unwrap();
-
-                    unsafe {
-                        Message::new(pid, vec![1, 2, 3, 4].leak()).send();
+                "msgparent" => 'a: {
+                    let Some(pid) = (unsafe { service::lookup("org.ChefKiss.PCIKit.Config") })
+                    else {
+                        writeln!(KWriter, "PCIKit is not running").unwrap();
+                        break 'a;
+                    };
+
+                    if let Err(e) = unsafe { Message::new(pid, vec![1, 2, 3, 4].leak()).send() } {
+                        writeln!(KWriter, "Failed to send message: {e:?}").unwrap();
                     }
                 }
                 "accessinvalid" => unsafe {
@@ -219,8 +238,10 @@ extern "C" fn _start(instance: OSDTEntry) -> ! {
                         writeln!(KWriter, "Expected data").unwrap();
                         break 'a;
                     };
-                    unsafe {
-                        Message::new(pid, data.to_be_bytes().to_vec().leak()).send();
+                    if let Err(e) =
+                        unsafe { Message::new(pid, data.to_be_bytes().to_vec().leak()).send() }
+                    {
+                        writeln!(KWriter, "Failed to send message: {e:?}").unwrap();
                     }
                 }
                 _ => writeln!(KWriter, "{s}").unwrap(),
@@ -2,6 +2,8 @@
 
 use arrayvec::ArrayString;
 
+use crate::cr::ExtendedControlReg0;
+
 #[bitfield(u32)]
 pub struct FeaturesMisc {
     pub brand_id: u8,
@@ -31,7 +33,7 @@ pub struct CPUFeatures {
     __: bool,
     pub movbe: bool,
     pub popcnt: bool,
-    __: bool,
+    pub tsc_deadline: bool,
     pub aes: bool,
     pub xsave: bool,
     pub osxsave: bool,
@@ -111,3 +113,33 @@ impl CPUIdentification {
         }
     }
 }
+
+/// `XSAVE` features, from function `0xD`.
+#[derive(Debug, Clone, Copy)]
+pub struct ExtendedStateInfo {
+    /// Components that may be enabled in XCR0.
+    pub supported: ExtendedControlReg0,
+    /// Size of the `XSAVE` area for the components currently enabled in XCR0.
+    pub enabled_size: u32,
+    /// Size of the `XSAVE` area if every supported component were enabled.
+    pub max_size: u32,
+}
+
+impl ExtendedStateInfo {
+    #[inline]
+    #[must_use]
+    pub fn new(ident: &CPUIdentification) -> Option<Self> {
+        if ident.largest_func_id < 0xD || !ident.features.xsave() {
+            return None;
+        }
+
+        // Newer toolchains made the intrinsic safe to call.
+        #[allow(unused_unsafe)]
+        let res = unsafe { core::arch::x86_64::__cpuid_count(0xD, 0) };
+        Some(Self {
+            supported: ExtendedControlReg0::from(u64::from(res.eax) | (u64::from(res.edx) << 32)),
+            enabled_size: res.ebx,
+            max_size: res.ecx,
+        })
+    }
+}
@@ -0,0 +1,114 @@
+// Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+#[bitfield(u64)]
+pub struct ControlReg0 {
+    pub protected_mode: bool,
+    pub monitor_coprocessor: bool,
+    pub emulation: bool,
+    pub task_switched: bool,
+    pub extension_type: bool,
+    pub numeric_error: bool,
+    #[bits(10)]
+    __: u16,
+    pub write_protect: bool,
+    __: bool,
+    pub alignment_mask: bool,
+    #[bits(10)]
+    __: u16,
+    pub not_write_through: bool,
+    pub cache_disable: bool,
+    pub paging: bool,
+    __: u32,
+}
+
+impl ControlReg0 {
+    #[must_use]
+    pub unsafe fn read() -> Self {
+        let value: u64;
+        core::arch::asm!("mov {}, cr0", out(reg) value, options(nomem, nostack, preserves_flags));
+        Self::from(value)
+    }
+
+    pub unsafe fn write(self) {
+        core::arch::asm!("mov cr0, {}", in(reg) u64::from(self), options(nostack, preserves_flags));
+    }
+}
+
+#[bitfield(u64)]
+pub struct ControlReg4 {
+    pub virtual_8086_ext: bool,
+    pub protected_virtual_ints: bool,
+    pub timestamp_disable: bool,
+    pub debugging_ext: bool,
+    pub page_size_ext: bool,
+    pub phys_addr_ext: bool,
+    pub machine_check: bool,
+    pub global_pages: bool,
+    pub perf_counter: bool,
+    /// `FXSAVE`/`FXRSTOR` include the SSE state, and SSE instructions are usable.
+    pub os_fxsr: bool,
+    /// Unmasked SIMD floating-point exceptions raise `#XM` instead of `#UD`.
+    pub os_xmm_exceptions: bool,
+    pub user_mode_ins_prevention: bool,
+    pub la57: bool,
+    pub vmx: bool,
+    pub smx: bool,
+    __: bool,
+    pub fs_gs_base: bool,
+    pub pcid: bool,
+    /// `XSAVE` and `XGETBV`/`XSETBV` are usable.
+    pub os_xsave: bool,
+    pub key_locker: bool,
+    pub smep: bool,
+    pub smap: bool,
+    pub protection_keys: bool,
+    pub control_flow_enforcement: bool,
+    pub supervisor_protection_keys: bool,
+    #[bits(39)]
+    __: u64,
+}
+
+impl ControlReg4 {
+    #[must_use]
+    pub unsafe fn read() -> Self {
+        let value: u64;
+        core::arch::asm!("mov {}, cr4", out(reg) value, options(nomem, nostack, preserves_flags));
+        Self::from(value)
+    }
+
+    pub unsafe fn write(self) {
+        core::arch::asm!("mov cr4, {}", in(reg) u64::from(self), options(nostack, preserves_flags));
+    }
+}
+
+/// State components `XSAVE` manages. Requires [`ControlReg4::os_xsave`].
+#[bitfield(u64)]
+pub struct ExtendedControlReg0 {
+    pub x87: bool,
+    pub sse: bool,
+    pub avx: bool,
+    pub mpx_bounds: bool,
+    pub mpx_config: bool,
+    pub avx512_opmask: bool,
+    pub avx512_zmm_hi256: bool,
+    pub avx512_hi16_zmm: bool,
+    __: bool,
+    pub pkru: bool,
+    #[bits(54)]
+    __: u64,
+}
+
+impl ExtendedControlReg0 {
+    #[must_use]
+    pub unsafe fn read() -> Self {
+        let (low, high): (u32, u32);
+        core::arch::asm!("xgetbv", in("ecx") 0, out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+        Self::from((u64::from(high) << 32) | u64::from(low))
+    }
+
+    pub unsafe fn write(self) {
+        let value = u64::from(self);
+        let (low, high): (u32, u32) = (value as u32, (value >> 32) as u32);
+        core::arch::asm!("xsetbv", in("ecx") 0, in("eax") low, in("edx") high, options(nostack, preserves_flags));
+    }
+}
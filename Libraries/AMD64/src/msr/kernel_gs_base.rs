@@ -0,0 +1,11 @@
+// Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+#[bitfield(u64)]
+pub struct KernelGsBase {
+    /// Base address swapped into `GS` by `SWAPGS`.
+    pub base: u64,
+}
+
+impl super::ModelSpecificReg for KernelGsBase {
+    const MSR_NUM: u32 = 0xC000_0102;
+}
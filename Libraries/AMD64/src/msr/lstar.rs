@@ -0,0 +1,11 @@
+// Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+#[bitfield(u64)]
+pub struct LongSysCallTargetAddr {
+    /// Long mode `SYSCALL` target RIP.
+    pub target_rip: u64,
+}
+
+impl super::ModelSpecificReg for LongSysCallTargetAddr {
+    const MSR_NUM: u32 = 0xC000_0082;
+}
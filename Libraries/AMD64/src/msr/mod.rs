@@ -2,7 +2,14 @@
 
 pub mod apic;
 pub mod efer;
+pub mod fs_base;
+pub mod gs_base;
+pub mod kernel_gs_base;
+pub mod lstar;
 pub mod pat;
+pub mod sfmask;
+pub mod star;
+pub mod tsc_deadline;
 pub mod vm_cr;
 
 pub trait ModelSpecificReg: Sized + From<u64> {
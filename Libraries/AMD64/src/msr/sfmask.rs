@@ -0,0 +1,12 @@
+// Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+#[bitfield(u64)]
+pub struct SysCallFlagMask {
+    /// RFLAGS bits cleared by `SYSCALL`.
+    pub mask: u32,
+    __: u32,
+}
+
+impl super::ModelSpecificReg for SysCallFlagMask {
+    const MSR_NUM: u32 = 0xC000_0084;
+}
@@ -0,0 +1,15 @@
+// Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+#[bitfield(u64)]
+pub struct SysCallTargetAddr {
+    /// Legacy mode `SYSCALL` target EIP.
+    pub target_eip: u32,
+    /// `SYSCALL` loads CS from this selector, and SS from the following one.
+    pub syscall_cs_ss: u16,
+    /// `SYSRET` to long mode loads SS from this selector + 8, and CS from this selector + 16.
+    pub sysret_cs_ss: u16,
+}
+
+impl super::ModelSpecificReg for SysCallTargetAddr {
+    const MSR_NUM: u32 = 0xC000_0081;
+}
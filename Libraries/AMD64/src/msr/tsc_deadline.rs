@@ -0,0 +1,11 @@
+// Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+#[bitfield(u64)]
+pub struct TscDeadline {
+    /// TSC value at which the LAPIC timer fires, 0 to disarm it.
+    pub deadline: u64,
+}
+
+impl super::ModelSpecificReg for TscDeadline {
+    const MSR_NUM: u32 = 0x6E0;
+}
@@ -1,5 +1,7 @@
 // Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
 
+pub mod buddy;
+
 pub const PAGE_SIZE: u64 = 0x1000;
 pub const PAGE_MASK: u64 = 0xFFF;
 
@@ -0,0 +1,206 @@
+// Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use super::PAGE_SIZE;
+
+/// Largest block is 2^`MAX_ORDER` pages, 1 GiB.
+pub const MAX_ORDER: usize = 18;
+const NONE: u32 = u32::MAX;
+const NOT_HEAD: u8 = u8::MAX;
+
+/// Free list links of a block.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Link {
+    next: u32,
+    prev: u32,
+}
+
+/// Binary buddy allocator. Free memory is kept as naturally aligned blocks of 2^order pages in one
+/// list per order, and a freed block merges with its buddy for as long as the latter is free too.
+///
+/// All state lives in caller provided slices, so the allocator makes no assumption about where
+/// the pages it hands out are mapped.
+pub struct BuddyAllocator<'a> {
+    /// Set for every page that is allocated or not usable.
+    bitmap: &'a mut [u64],
+    /// Order of the free block starting at each page, or [`NOT_HEAD`].
+    orders: &'a mut [u8],
+    /// Free list links of the block starting at each page.
+    links: &'a mut [Link],
+    heads: [u32; MAX_ORDER + 1],
+    /// Number of free blocks of each order.
+    pub free_blocks: [u64; MAX_ORDER + 1],
+    pub free_pages: u64,
+    pub total_pages: u64,
+}
+
+impl<'a> BuddyAllocator<'a> {
+    /// Bytes of metadata needed for `total_pages` pages: the bitmap, then the links, then the
+    /// orders.
+    #[must_use]
+    pub const fn metadata_size(total_pages: u64) -> (u64, u64, u64) {
+        (
+            total_pages.div_ceil(64) * 8,
+            total_pages * core::mem::size_of::<Link>() as u64,
+            total_pages,
+        )
+    }
+
+    /// Creates an allocator for as many pages as `orders` has entries, all of them allocated.
+    /// `bitmap` needs a bit and `links` an entry for each page too.
+    pub fn new(bitmap: &'a mut [u64], orders: &'a mut [u8], links: &'a mut [Link]) -> Self {
+        let total_pages = orders.len() as u64;
+        assert!(total_pages < u64::from(NONE), "Too much memory");
+        assert!(bitmap.len() as u64 * 64 >= total_pages && links.len() as u64 >= total_pages);
+        bitmap.fill(!0u64);
+        orders.fill(NOT_HEAD);
+
+        Self {
+            bitmap,
+            orders,
+            links,
+            heads: [NONE; MAX_ORDER + 1],
+            free_blocks: [0; MAX_ORDER + 1],
+            free_pages: 0,
+            total_pages,
+        }
+    }
+
+    const fn order_for(count: u64) -> usize {
+        count.next_power_of_two().trailing_zeros() as usize
+    }
+
+    fn push(&mut self, page: u32, order: usize) {
+        let head = self.heads[order];
+        self.links[page as usize] = Link {
+            next: head,
+            prev: NONE,
+        };
+        if head != NONE {
+            self.links[head as usize].prev = page;
+        }
+        self.heads[order] = page;
+        self.orders[page as usize] = order as u8;
+        self.free_blocks[order] += 1;
+    }
+
+    fn unlink(&mut self, page: u32, order: usize) {
+        let Link { next, prev } = self.links[page as usize];
+        if prev == NONE {
+            self.heads[order] = next;
+        } else {
+            self.links[prev as usize].next = next;
+        }
+        if next != NONE {
+            self.links[next as usize].prev = prev;
+        }
+        self.orders[page as usize] = NOT_HEAD;
+        self.free_blocks[order] -= 1;
+    }
+
+    /// Adds the block to the free lists, merged with its buddies where possible.
+    fn free_block(&mut self, mut page: u64, mut order: usize) {
+        while order < MAX_ORDER {
+            let buddy = page ^ (1 << order);
+            // A free page heading a block must head the whole buddy, as blocks are aligned to
+            // their size. Its order says whether the buddy is free in its entirety.
+            if buddy >= self.total_pages || self.orders[buddy as usize] != order as u8 {
+                break;
+            }
+            self.unlink(buddy as u32, order);
+            page = page.min(buddy);
+            order += 1;
+        }
+        self.push(page as u32, order);
+    }
+
+    /// Adds any page range to the free lists, as the largest aligned blocks that fit.
+    fn release(&mut self, mut page: u64, mut count: u64) {
+        while count != 0 {
+            let order = (page.trailing_zeros() as usize)
+                .min(63 - count.leading_zeros() as usize)
+                .min(MAX_ORDER);
+            self.free_block(page, order);
+            page += 1 << order;
+            count -= 1 << order;
+        }
+    }
+
+    fn set_allocated(&mut self, page: u64, allocated: bool) {
+        let (index, bit) = ((page / 64) as usize, 1u64 << (page % 64));
+        if allocated {
+            self.bitmap[index] |= bit;
+        } else {
+            self.bitmap[index] &= !bit;
+        }
+    }
+
+    /// Hands out the first `count` pages of the free block of order `from` at `page`, which was
+    /// taken off its list. The block is split down to `order` and the rest is given back.
+    fn take(&mut self, page: u32, from: usize, order: usize, count: u64) -> *mut u8 {
+        for v in (order..from).rev() {
+            self.push(page + (1 << v), v);
+        }
+
+        let page = u64::from(page);
+        for i in page..page + count {
+            self.set_allocated(i, true);
+        }
+        self.free_pages -= count;
+        self.release(page + count, (1 << order) - count);
+
+        (page * PAGE_SIZE) as *mut _
+    }
+
+    pub unsafe fn alloc(&mut self, count: u64) -> Option<*mut u8> {
+        let order = Self::order_for(count);
+        let from = (order..=MAX_ORDER).find(|&v| self.heads[v] != NONE)?;
+        let page = self.heads[from];
+        self.unlink(page, from);
+        Some(self.take(page, from, order, count))
+    }
+
+    /// Allocates `count` contiguous pages starting on a multiple of `align` pages and ending at or
+    /// below `max_addr`.
+    pub unsafe fn alloc_aligned(
+        &mut self,
+        count: u64,
+        align: u64,
+        max_addr: u64,
+    ) -> Option<*mut u8> {
+        // Blocks are aligned to their size.
+        let order = Self::order_for(count).max(Self::order_for(align));
+        let limit = (max_addr / PAGE_SIZE).min(self.total_pages);
+
+        for from in order..=MAX_ORDER {
+            let mut page = self.heads[from];
+            while page != NONE {
+                if u64::from(page) + (1 << order) <= limit {
+                    self.unlink(page, from);
+                    return Some(self.take(page, from, order, count));
+                }
+                page = self.links[page as usize].next;
+            }
+        }
+
+        None
+    }
+
+    /// Frees `count` pages from `ptr`, which may be any part of earlier allocations.
+    pub unsafe fn free(&mut self, ptr: *mut u8, count: u64) {
+        let idx = ptr as u64 / PAGE_SIZE;
+
+        for i in idx..(idx + count) {
+            self.set_allocated(i, false);
+        }
+
+        self.free_pages += count;
+        self.release(idx, count);
+    }
+
+    #[must_use]
+    pub fn is_allocated(&self, ptr: *mut u8, count: u64) -> bool {
+        let idx = ptr as u64 / PAGE_SIZE;
+
+        (idx..(idx + count)).all(|i| self.bitmap[(i / 64) as usize] & (1u64 << (i % 64)) != 0)
+    }
+}
@@ -0,0 +1,141 @@
+// Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+#![deny(warnings, clippy::nursery, unused_extern_crates)]
+
+use amd64::paging::{
+    buddy::{BuddyAllocator, Link},
+    PAGE_SIZE,
+};
+
+fn with_allocator(total_pages: usize, f: impl FnOnce(&mut BuddyAllocator)) {
+    let mut bitmap = vec![0u64; total_pages.div_ceil(64)];
+    let mut orders = vec![0u8; total_pages];
+    let mut links = vec![Link::default(); total_pages];
+    f(&mut BuddyAllocator::new(
+        &mut bitmap,
+        &mut orders,
+        &mut links,
+    ));
+}
+
+const fn page(index: u64) -> *mut u8 {
+    (index * PAGE_SIZE) as *mut u8
+}
+
+#[test]
+fn test_split_merge() {
+    with_allocator(64, |buddy| unsafe {
+        buddy.free(page(0), 64);
+        assert_eq!(buddy.free_blocks[6], 1);
+
+        let ptr = buddy.alloc(1).unwrap();
+        assert_eq!(ptr, page(0));
+        assert_eq!(buddy.free_blocks[..7], [1, 1, 1, 1, 1, 1, 0]);
+
+        buddy.free(ptr, 1);
+        assert_eq!(buddy.free_blocks[..7], [0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(buddy.free_pages, 64);
+    });
+}
+
+#[test]
+fn test_merge_stops_at_end() {
+    with_allocator(48, |buddy| unsafe {
+        buddy.free(page(0), 48);
+        assert_eq!(buddy.free_blocks[..7], [0, 0, 0, 0, 1, 1, 0]);
+        assert_eq!(buddy.alloc(32), Some(page(0)));
+        assert_eq!(buddy.alloc(32), None);
+    });
+}
+
+#[test]
+fn test_release_unaligned() {
+    with_allocator(32, |buddy| unsafe {
+        buddy.free(page(3), 10);
+        assert_eq!(buddy.free_blocks[..4], [2, 0, 2, 0]);
+        assert_eq!(buddy.free_pages, 10);
+
+        buddy.free(page(0), 3);
+        buddy.free(page(13), 19);
+        assert_eq!(buddy.free_blocks[..6], [0, 0, 0, 0, 0, 1]);
+        assert_eq!(buddy.free_pages, 32);
+    });
+}
+
+#[test]
+fn test_partial_free() {
+    with_allocator(16, |buddy| unsafe {
+        buddy.free(page(0), 16);
+        let ptr = buddy.alloc(16).unwrap();
+        buddy.free(ptr.add(5 * PAGE_SIZE as usize), 6);
+        assert_eq!(buddy.free_pages, 6);
+        assert!(buddy.is_allocated(ptr, 5));
+        assert!(buddy.is_allocated(page(11), 5));
+
+        buddy.free(ptr, 5);
+        buddy.free(page(11), 5);
+        assert_eq!(buddy.free_blocks[4], 1);
+    });
+}
+
+#[test]
+fn test_alloc_aligned() {
+    with_allocator(64, |buddy| unsafe {
+        buddy.free(page(0), 64);
+        assert_eq!(buddy.alloc(1), Some(page(0)));
+
+        let ptr = buddy.alloc_aligned(3, 16, u64::MAX).unwrap();
+        assert_eq!(ptr as u64 % (16 * PAGE_SIZE), 0);
+        assert!(buddy.is_allocated(ptr, 3));
+        assert!(!buddy.is_allocated(ptr.add(3 * PAGE_SIZE as usize), 1));
+        assert_eq!(buddy.free_pages, 60);
+    });
+}
+
+#[test]
+fn test_alloc_aligned_max_addr() {
+    with_allocator(64, |buddy| unsafe {
+        buddy.free(page(32), 32);
+        assert_eq!(buddy.alloc_aligned(1, 1, 32 * PAGE_SIZE), None);
+        assert_eq!(buddy.alloc_aligned(8, 8, 39 * PAGE_SIZE), None);
+        assert_eq!(buddy.alloc_aligned(8, 8, 40 * PAGE_SIZE), Some(page(32)));
+        assert_eq!(buddy.alloc_aligned(1, 1, 40 * PAGE_SIZE), None);
+        assert_eq!(buddy.free_pages, 24);
+    });
+}
+
+#[test]
+fn test_stats() {
+    with_allocator(100, |buddy| unsafe {
+        assert_eq!(buddy.total_pages, 100);
+        assert_eq!(buddy.free_pages, 0);
+
+        buddy.free(page(0), 100);
+        assert_eq!(buddy.free_pages, 100);
+        assert_eq!(buddy.free_blocks[..7], [0, 0, 1, 0, 0, 1, 1]);
+
+        let ptr = buddy.alloc(5).unwrap();
+        assert_eq!(buddy.free_pages, 95);
+        assert_eq!(buddy.free_blocks.iter().sum::<u64>(), 6);
+
+        buddy.free(ptr, 5);
+        assert_eq!(buddy.free_pages, 100);
+        assert_eq!(buddy.free_blocks[..7], [0, 0, 1, 0, 0, 1, 1]);
+    });
+}
+
+#[test]
+fn test_is_allocated() {
+    with_allocator(8, |buddy| unsafe {
+        assert!(buddy.is_allocated(page(0), 8));
+
+        buddy.free(page(2), 4);
+        assert!(buddy.is_allocated(page(0), 2));
+        assert!(!buddy.is_allocated(page(0), 3));
+        assert!(!buddy.is_allocated(page(5), 1));
+        assert!(buddy.is_allocated(page(6), 2));
+
+        let ptr = buddy.alloc(2).unwrap();
+        assert!(buddy.is_allocated(ptr, 2));
+    });
+}
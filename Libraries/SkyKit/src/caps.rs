@@ -0,0 +1,111 @@
+// Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use alloc::vec::Vec;
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "userspace")]
+use crate::{
+    syscall::{syscall, SystemCall},
+    SkyError,
+};
+
+/// Hardware and OSDT resources a process may touch.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SKCapabilities {
+    /// Inclusive I/O port ranges.
+    #[serde(default)]
+    pub ports: Vec<(u16, u16)>,
+    #[serde(default)]
+    pub irqs: Vec<u8>,
+    /// Inclusive physical address ranges that may be mapped as MMIO.
+    #[serde(default)]
+    pub mmio: Vec<(u64, u64)>,
+    /// OSDT entries whose subtree may be modified.
+    /// Every extension implicitly holds the entry it was attached to.
+    #[serde(default)]
+    pub osdt_entries: Vec<u64>,
+}
+
+impl SKCapabilities {
+    /// Whether an access of `len` bytes starting at `port` stays within a single granted range.
+    #[must_use]
+    pub fn allows_ports(&self, port: u16, len: u16) -> bool {
+        let Some(last) = port.checked_add(len.saturating_sub(1)) else {
+            return false;
+        };
+        self.allows_port_range(port, last)
+    }
+
+    /// Whether the inclusive range `first..=last` stays within a single granted range.
+    #[must_use]
+    pub fn allows_port_range(&self, first: u16, last: u16) -> bool {
+        self.ports
+            .iter()
+            .any(|&(start, end)| start <= first && last <= end)
+    }
+
+    #[must_use]
+    pub fn allows_irq(&self, irq: u8) -> bool {
+        self.irqs.contains(&irq)
+    }
+
+    /// Whether `size` bytes starting at physical address `addr` stay within a single granted range.
+    #[must_use]
+    pub fn allows_mmio(&self, addr: u64, size: u64) -> bool {
+        let Some(last) = addr.checked_add(size.saturating_sub(1)) else {
+            return false;
+        };
+        self.allows_mmio_range(addr, last)
+    }
+
+    #[must_use]
+    pub fn allows_mmio_range(&self, first: u64, last: u64) -> bool {
+        self.mmio
+            .iter()
+            .any(|&(start, end)| start <= first && last <= end)
+    }
+
+    /// Whether every port range, IRQ and MMIO range in `other` is also held by `self`.
+    /// OSDT entries are not compared, as that requires walking the tree.
+    #[must_use]
+    pub fn covers(&self, other: &Self) -> bool {
+        other
+            .ports
+            .iter()
+            .all(|&(start, end)| start <= end && self.allows_port_range(start, end))
+            && other.irqs.iter().all(|&v| self.allows_irq(v))
+            && other
+                .mmio
+                .iter()
+                .all(|&(start, end)| start <= end && self.allows_mmio_range(start, end))
+    }
+
+    pub fn extend(&mut self, other: Self) {
+        self.ports.extend(other.ports);
+        self.irqs.extend(other.irqs);
+        self.mmio.extend(other.mmio);
+        self.osdt_entries.extend(other.osdt_entries);
+        self.ports.sort_unstable();
+        self.ports.dedup();
+        self.irqs.sort_unstable();
+        self.irqs.dedup();
+        self.mmio.sort_unstable();
+        self.mmio.dedup();
+        self.osdt_entries.sort_unstable();
+        self.osdt_entries.dedup();
+    }
+}
+
+/// Hands a subset of the calling process' capabilities to one of its children.
+#[cfg(feature = "userspace")]
+pub unsafe fn grant(pid: u64, caps: &SKCapabilities) -> Result<(), SkyError> {
+    let data = postcard::to_allocvec(caps).unwrap();
+    let err = syscall!(
+        SystemCall::GrantCapabilities,
+        in("rsi") pid,
+        in("rdx") data.as_ptr() as u64,
+        in("r10") data.len() as u64,
+    );
+    SkyError::from_raw(err)
+}
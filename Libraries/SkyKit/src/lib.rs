@@ -3,7 +3,7 @@
 #![no_std]
 #![deny(warnings, clippy::nursery, unused_extern_crates)]
 #![allow(clippy::missing_safety_doc)]
-#![cfg_attr(feature = "userspace", feature(alloc_error_handler))]
+#![cfg_attr(feature = "userspace", feature(alloc_error_handler, sync_unsafe_cell))]
 
 use alloc::{string::String, vec::Vec};
 
@@ -14,21 +14,28 @@ extern crate alloc;
 #[macro_use]
 extern crate log;
 
+pub mod caps;
 pub mod msg;
 pub mod osdtentry;
 pub mod osvalue;
+pub mod priority;
+pub mod process;
+pub mod rpc;
+#[cfg(feature = "userspace")]
+pub mod service;
 pub mod syscall;
 #[cfg(feature = "userspace")]
 pub mod userspace;
 
+use num_enum::{IntoPrimitive, TryFromPrimitive};
 use serde::{Deserialize, Serialize};
 
-pub const USER_VIRT_OFFSET: u64 = 0xC000_0000;
-
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct SKExtension {
     pub identifier: String,
     pub personalities: HashMap<String, HashMap<String, osvalue::OSValue>>,
+    #[serde(default)]
+    pub capabilities: caps::SKCapabilities,
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
@@ -40,6 +47,28 @@ impl SKExtensions {
     }
 }
 
+/// Recoverable syscall failure, reported in `r9` with 0 meaning success.
+#[derive(
+    Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, IntoPrimitive, TryFromPrimitive,
+)]
+#[repr(u64)]
+pub enum SkyError {
+    NotFound = 1,
+    AlreadyExists,
+    InsufficientPermissions,
+    InvalidArgument,
+    OutOfMemory,
+}
+
+impl SkyError {
+    pub fn from_raw(v: u64) -> Result<(), Self> {
+        match v {
+            0 => Ok(()),
+            v => Err(Self::try_from(v).expect("Unknown syscall error")),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
 pub enum TerminationReason {
     Unspecified,
@@ -49,4 +78,5 @@ pub enum TerminationReason {
     NotFound,
     AlreadyExists,
     InsufficientPermissions,
+    UnhandledException,
 }
@@ -1,9 +1,32 @@
 // Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
 
+use alloc::{collections::VecDeque, string::String};
+
 use serde::{Deserialize, Serialize};
 
 #[cfg(feature = "userspace")]
-use super::syscall::SystemCall;
+use super::{
+    syscall::{syscall, SystemCall},
+    userspace::sync::{Condvar, Mutex},
+    SkyError,
+};
+
+#[cfg(feature = "userspace")]
+struct Inbox {
+    /// Messages that were received while waiting for other ones.
+    mailbox: Mailbox,
+    /// Whether a thread is already waiting for the kernel to deliver a message.
+    receiving: bool,
+}
+
+#[cfg(feature = "userspace")]
+static INBOX: Mutex<Inbox> = Mutex::new(Inbox {
+    mailbox: Mailbox::new(),
+    receiving: false,
+});
+/// Notified whenever the receiving thread got a message.
+#[cfg(feature = "userspace")]
+static ARRIVED: Condvar = Condvar::new();
 
 #[derive(Debug, Clone)]
 pub struct Message {
@@ -28,20 +51,48 @@ impl Message {
     }
 }
 
+/// Messages set aside while waiting for specific ones, in arrival order.
+#[derive(Debug, Default)]
+pub struct Mailbox {
+    pending: VecDeque<Message>,
+}
+
+impl Mailbox {
+    #[inline]
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Takes the oldest message satisfying `pred` that was set aside.
+    pub fn take(&mut self, pred: impl FnMut(&Message) -> bool) -> Option<Message> {
+        let i = self.pending.iter().position(pred)?;
+        self.pending.remove(i)
+    }
+
+    /// Hands back a newly received `msg` if it satisfies `pred`, or sets it aside otherwise.
+    pub fn sort(&mut self, msg: Message, pred: impl FnOnce(&Message) -> bool) -> Option<Message> {
+        if pred(&msg) {
+            return Some(msg);
+        }
+        self.pending.push_back(msg);
+        None
+    }
+}
+
 #[cfg(feature = "userspace")]
 impl Message {
-    #[must_use]
-    pub unsafe fn recv() -> Self {
+    unsafe fn recv_raw() -> Self {
         let (mut id, mut pid): (u64, u64);
         let (mut ptr, mut len): (u64, u64);
-        core::arch::asm!(
-            "int 249",
-            in("rdi") SystemCall::MsgRecv as u64,
+        syscall!(
+            SystemCall::MsgRecv,
             out("rax") id,
             lateout("rdi") pid,
             out("rsi") ptr,
             out("rdx") len,
-            options(nostack),
         );
         Self {
             id,
@@ -50,15 +101,100 @@ impl Message {
         }
     }
 
-    pub unsafe fn send(self) {
-        core::arch::asm!(
-            "int 249",
-            in("rdi") SystemCall::MsgSend as u64,
+    /// `from` of `None` accepts any sender, and a `timeout` of `None` waits forever.
+    unsafe fn recv_raw_filtered(from: Option<u64>, timeout: Option<u64>) -> Option<Self> {
+        let (mut id, mut pid): (u64, u64);
+        let (mut ptr, mut len): (u64, u64);
+        syscall!(
+            SystemCall::MsgRecvFiltered,
+            in("rsi") from.unwrap_or(u64::MAX),
+            in("rdx") timeout.unwrap_or(u64::MAX),
+            out("rax") id,
+            lateout("rdi") pid,
+            lateout("rsi") ptr,
+            lateout("rdx") len,
+        );
+        (id != 0).then(|| Self {
+            id,
+            pid,
+            data: core::slice::from_raw_parts(ptr as *const u8, len as _),
+        })
+    }
+
+    #[must_use]
+    pub unsafe fn recv() -> Self {
+        Self::recv_matching(|_| true)
+    }
+
+    /// Receives the oldest message sent by `from`, or by anyone if `None`.
+    /// Gives up after `timeout_ms` milliseconds, or waits forever if `None`.
+    #[must_use]
+    pub unsafe fn recv_filtered(from: Option<u64>, timeout_ms: Option<u64>) -> Option<Self> {
+        let accepts = |v: &Self| from.is_none_or(|from| v.pid == from);
+        if timeout_ms.is_none() {
+            return Some(Self::recv_matching(accepts));
+        }
+        if let Some(v) = INBOX.lock().mailbox.take(accepts) {
+            return Some(v);
+        }
+
+        // Another thread may have set the message aside while this one was waiting.
+        Self::recv_raw_filtered(from, timeout_ms).or_else(|| INBOX.lock().mailbox.take(accepts))
+    }
+
+    #[must_use]
+    pub unsafe fn try_recv() -> Option<Self> {
+        Self::recv_filtered(None, Some(0))
+    }
+
+    #[must_use]
+    pub unsafe fn recv_timeout(timeout_ms: u64) -> Option<Self> {
+        Self::recv_filtered(None, Some(timeout_ms))
+    }
+
+    /// Waits for a message from `pid`, leaving messages from anyone else queued.
+    #[must_use]
+    pub unsafe fn recv_from(pid: u64) -> Self {
+        Self::recv_filtered(Some(pid), None).unwrap()
+    }
+
+    /// Waits for a message satisfying `pred`.
+    /// Any other message received in the meantime is kept for later `recv` calls.
+    /// Only one thread waits on the kernel at a time and hands what it gets to the others, so
+    /// none of them can miss a message set aside by another.
+    #[must_use]
+    pub unsafe fn recv_matching(mut pred: impl FnMut(&Self) -> bool) -> Self {
+        let mut inbox = INBOX.lock();
+        loop {
+            if let Some(v) = inbox.mailbox.take(&mut pred) {
+                return v;
+            }
+            if inbox.receiving {
+                inbox = ARRIVED.wait(inbox);
+                continue;
+            }
+
+            inbox.receiving = true;
+            drop(inbox);
+            let msg = Self::recv_raw();
+            inbox = INBOX.lock();
+            inbox.receiving = false;
+            ARRIVED.notify_all();
+            if let Some(v) = inbox.mailbox.sort(msg, &mut pred) {
+                return v;
+            }
+        }
+    }
+
+    /// The data is handed over to the kernel, even if the message could not be delivered.
+    pub unsafe fn send(self) -> Result<(), SkyError> {
+        let err = syscall!(
+            SystemCall::MsgSend,
             in("rsi") self.pid,
             in("rdx") self.data.as_ptr() as u64,
-            in("rcx") self.data.len() as u64,
-            options(nostack),
+            in("r10") self.data.len() as u64,
         );
+        SkyError::from_raw(err)
     }
 }
 
@@ -69,18 +205,29 @@ impl Drop for Message {
             return;
         }
         unsafe {
-            core::arch::asm!(
-                "int 249",
-                in("rdi") SystemCall::MsgAck as u64,
+            syscall!(
+                SystemCall::MsgAck,
                 in("rsi") self.id,
-                options(nostack),
             );
         }
     }
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[repr(C)]
 pub enum KernelMessage {
     IRQFired(u8),
+    /// A watched service name was registered by the given PID.
+    ServiceRegistered(String, u64),
+    /// A watched service name was unregistered or its owner exited.
+    ServiceUnregistered(String),
+    /// A timer created by the process expired.
+    TimerFired(u64),
+    /// A watched process exited. `reason` is set if the kernel killed it,
+    /// in which case `code` is `u64::MAX`.
+    ProcessExited {
+        pid: u64,
+        code: u64,
+        reason: Option<super::TerminationReason>,
+    },
 }
@@ -11,11 +11,17 @@ use serde::{Deserialize, Serialize};
 
 use crate::osvalue::OSValue;
 #[cfg(feature = "userspace")]
-use crate::syscall::SystemCall;
+use crate::{
+    syscall::{syscall, SystemCall},
+    SkyError,
+};
 
 pub const OSDTENTRY_NAME_KEY: &str = "_Name";
+pub const SKEXT_KEY_PREFIX: &str = "_SKExt";
 pub const SKEXT_MATCH_KEY: &str = "_SKExtMatch";
 pub const SKEXT_PROC_KEY: &str = "_SKExtProc";
+/// Replaces [`SKEXT_PROC_KEY`] with the exit code once the extension's process is gone.
+pub const SKEXT_EXIT_KEY: &str = "_SKExtExit";
 
 #[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
 #[repr(transparent)]
@@ -36,75 +42,67 @@ pub struct OSDTEntryProp(pub String, pub OSValue);
 
 #[cfg(feature = "userspace")]
 impl OSDTEntry {
-    fn get_info(&self, ty: OSDTEntryInfo, k: Option<&str>) -> Vec<u8> {
+    fn get_info(&self, ty: OSDTEntryInfo, k: Option<&str>) -> Result<Vec<u8>, SkyError> {
         let (mut ptr, mut len): (u64, u64);
         unsafe {
-            core::arch::asm!(
-                "int 249",
-                in("rdi") SystemCall::GetOSDTEntryInfo as u64,
+            let err = syscall!(
+                SystemCall::GetOSDTEntryInfo,
                 in("rsi") self.0,
                 in("rdx") ty as u64,
-                in("rcx") k.map_or(0, |s| s.as_ptr() as u64),
+                in("r10") k.map_or(0, |s| s.as_ptr() as u64),
                 in("r8") k.map_or(0, |s| s.len() as u64),
                 out("rax") ptr,
                 lateout("rdi") len,
-                options(nostack),
             );
-            Vec::from_raw_parts(ptr as *mut u8, len as _, len as _)
+            SkyError::from_raw(err)
+                .map(|()| Vec::from_raw_parts(ptr as *mut u8, len as _, len as _))
         }
     }
 
-    #[must_use]
-    pub fn new_child(&self, name: Option<&str>) -> Self {
+    pub fn new_child(&self, name: Option<&str>) -> Result<Self, SkyError> {
         let mut id: u64;
-        unsafe {
-            core::arch::asm!(
-                "int 249",
-                in("rdi") SystemCall::NewOSDTEntry as u64,
+        let err = unsafe {
+            syscall!(
+                SystemCall::NewOSDTEntry,
                 in("rsi") self.0,
                 out("rax") id,
-                options(nostack),
-            );
-        }
+            )
+        };
+        SkyError::from_raw(err)?;
         let ret: Self = id.into();
         if let Some(name) = name {
-            ret.set_property(OSDTENTRY_NAME_KEY, name.into());
+            ret.set_property(OSDTENTRY_NAME_KEY, name.into())?;
         }
-        ret
+        Ok(ret)
     }
 
-    #[must_use]
-    pub fn parent(&self) -> Option<Self> {
-        postcard::from_bytes(&self.get_info(OSDTEntryInfo::Parent, None)).unwrap()
+    pub fn parent(&self) -> Result<Option<Self>, SkyError> {
+        Ok(postcard::from_bytes(&self.get_info(OSDTEntryInfo::Parent, None)?).unwrap())
     }
 
-    #[must_use]
-    pub fn children(&self) -> Vec<Self> {
-        postcard::from_bytes(&self.get_info(OSDTEntryInfo::Children, None)).unwrap()
+    pub fn children(&self) -> Result<Vec<Self>, SkyError> {
+        Ok(postcard::from_bytes(&self.get_info(OSDTEntryInfo::Children, None)?).unwrap())
     }
 
-    #[must_use]
-    pub fn properties(&self) -> HashMap<String, OSValue> {
-        postcard::from_bytes(&self.get_info(OSDTEntryInfo::Properties, None)).unwrap()
+    pub fn properties(&self) -> Result<HashMap<String, OSValue>, SkyError> {
+        Ok(postcard::from_bytes(&self.get_info(OSDTEntryInfo::Properties, None)?).unwrap())
     }
 
-    #[must_use]
-    pub fn get_property(&self, k: &str) -> Option<OSValue> {
-        postcard::from_bytes(&self.get_info(OSDTEntryInfo::Property, Some(k))).unwrap()
+    pub fn get_property(&self, k: &str) -> Result<Option<OSValue>, SkyError> {
+        Ok(postcard::from_bytes(&self.get_info(OSDTEntryInfo::Property, Some(k))?).unwrap())
     }
 
-    pub fn set_property(&self, k: &str, v: OSValue) {
+    pub fn set_property(&self, k: &str, v: OSValue) -> Result<(), SkyError> {
         let req = postcard::to_allocvec(&OSDTEntryProp(k.to_owned(), v)).unwrap();
-        unsafe {
-            core::arch::asm!(
-                "int 249",
-                in("rdi") SystemCall::SetOSDTEntryProp as u64,
+        let err = unsafe {
+            syscall!(
+                SystemCall::SetOSDTEntryProp,
                 in("rsi") self.0,
                 in("rdx") req.as_ptr() as u64,
-                in("rcx") req.len() as u64,
-                options(nostack),
-            );
-        }
+                in("r10") req.len() as u64,
+            )
+        };
+        SkyError::from_raw(err)
     }
 }
 
@@ -0,0 +1,17 @@
+// Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+//! Thread priorities. Higher values run first, and threads of equal priority take turns.
+
+pub const LEVELS: u8 = 32;
+pub const DEFAULT: u8 = 8;
+/// Highest priority of the normal class.
+pub const NORMAL_MAX: u8 = 15;
+/// The real-time class is reserved for processes allowed to handle IRQs.
+pub const REALTIME_MIN: u8 = NORMAL_MAX + 1;
+pub const MAX: u8 = LEVELS - 1;
+
+#[inline]
+#[must_use]
+pub const fn is_realtime(priority: u8) -> bool {
+    priority >= REALTIME_MIN
+}
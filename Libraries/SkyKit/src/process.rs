@@ -0,0 +1,96 @@
+// Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use alloc::{string::String, vec::Vec};
+
+use serde::{Deserialize, Serialize};
+
+use crate::osdtentry::OSDTEntry;
+#[cfg(feature = "userspace")]
+use crate::{
+    syscall::{syscall, SystemCall},
+    SkyError,
+};
+
+/// Starts a cached extension by identifier, rather than waiting for it to match.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpawnRequest {
+    pub identifier: String,
+    /// Passed to the entry point of the extension as a pointer and length, after its OSDT entry.
+    pub args: Vec<u8>,
+    /// Must be within the subtree of the caller.
+    pub parent: OSDTEntry,
+}
+
+#[cfg(feature = "userspace")]
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessHandle {
+    pid: u64,
+    entry: OSDTEntry,
+}
+
+#[cfg(feature = "userspace")]
+impl ProcessHandle {
+    #[inline]
+    #[must_use]
+    pub const fn pid(&self) -> u64 {
+        self.pid
+    }
+
+    /// The OSDT entry the process was attached to.
+    #[inline]
+    #[must_use]
+    pub const fn entry(&self) -> OSDTEntry {
+        self.entry
+    }
+}
+
+#[cfg(feature = "userspace")]
+pub unsafe fn spawn(
+    identifier: &str,
+    args: &[u8],
+    parent: OSDTEntry,
+) -> Result<ProcessHandle, SkyError> {
+    let req = SpawnRequest {
+        identifier: identifier.into(),
+        args: args.into(),
+        parent,
+    };
+    let data = postcard::to_allocvec(&req).unwrap();
+    let (pid, entry): (u64, u64);
+    let err = syscall!(
+        SystemCall::SpawnExtension,
+        in("rsi") data.as_ptr() as u64,
+        inout("rdx") data.len() as u64 => entry,
+        lateout("rax") pid,
+    );
+    SkyError::from_raw(err).map(|()| ProcessHandle {
+        pid,
+        entry: entry.into(),
+    })
+}
+
+/// Subscribes to [`crate::msg::KernelMessage::ProcessExited`] for `pid`.
+#[cfg(feature = "userspace")]
+pub unsafe fn watch(pid: u64) -> Result<(), SkyError> {
+    let err = syscall!(
+        SystemCall::WatchProcess,
+        in("rsi") pid,
+    );
+    SkyError::from_raw(err)
+}
+
+/// Ends the calling process, reporting `code` to its watchers.
+#[cfg(feature = "userspace")]
+pub unsafe fn exit(code: u64) -> ! {
+    SystemCall::quit(code)
+}
+
+/// Reconstructs the arguments an extension received at its entry point.
+#[cfg(feature = "userspace")]
+#[must_use]
+pub unsafe fn args(ptr: *const u8, len: usize) -> &'static [u8] {
+    if ptr.is_null() {
+        return &[];
+    }
+    core::slice::from_raw_parts(ptr, len)
+}
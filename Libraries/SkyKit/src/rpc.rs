@@ -0,0 +1,227 @@
+// Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use alloc::vec::Vec;
+#[cfg(feature = "userspace")]
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::msg::Message;
+#[cfg(feature = "userspace")]
+use crate::SkyError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RPCKind {
+    Request,
+    Notification,
+    Reply,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RPCHeader {
+    pub kind: RPCKind,
+    pub seq: u64,
+}
+
+impl RPCHeader {
+    #[inline]
+    #[must_use]
+    pub const fn new(kind: RPCKind, seq: u64) -> Self {
+        Self { kind, seq }
+    }
+
+    /// Decodes only the header of an encoded [`RPCPacket`], leaving the body untouched.
+    #[must_use]
+    pub fn peek(data: &[u8]) -> Option<Self> {
+        postcard::take_from_bytes(data).ok().map(|(v, _)| v)
+    }
+
+    /// Whether `msg` is the reply of `pid` to request `seq`.
+    #[must_use]
+    pub fn is_reply(msg: &Message, pid: u64, seq: u64) -> bool {
+        msg.pid == pid && Self::peek(msg.data) == Some(Self::new(RPCKind::Reply, seq))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RPCPacket<T> {
+    pub header: RPCHeader,
+    pub body: T,
+}
+
+impl<T> RPCPacket<T> {
+    #[inline]
+    #[must_use]
+    pub const fn new(kind: RPCKind, seq: u64, body: T) -> Self {
+        Self {
+            header: RPCHeader::new(kind, seq),
+            body,
+        }
+    }
+}
+
+impl<T: Serialize> RPCPacket<T> {
+    #[must_use]
+    pub fn to_vec(&self) -> Vec<u8> {
+        postcard::to_allocvec(self).unwrap()
+    }
+}
+
+impl<T: DeserializeOwned> RPCPacket<T> {
+    #[must_use]
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        postcard::from_bytes(data).ok()
+    }
+}
+
+/// Describes the request and response types spoken by a service.
+pub trait RPCService {
+    type Request: Serialize + DeserializeOwned;
+    type Response: Serialize + DeserializeOwned;
+}
+
+#[cfg(feature = "userspace")]
+static NEXT_SEQ: AtomicU64 = AtomicU64::new(1);
+
+#[cfg(feature = "userspace")]
+unsafe fn send_packet<T: Serialize>(pid: u64, packet: &RPCPacket<T>) -> Result<(), SkyError> {
+    Message::new(pid, packet.to_vec().leak()).send()
+}
+
+#[cfg(feature = "userspace")]
+pub struct RPCClient<S: RPCService> {
+    pid: u64,
+    __: core::marker::PhantomData<S>,
+}
+
+#[cfg(feature = "userspace")]
+impl<S: RPCService> Clone for RPCClient<S> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+#[cfg(feature = "userspace")]
+impl<S: RPCService> Copy for RPCClient<S> {}
+
+#[cfg(feature = "userspace")]
+impl<S: RPCService> RPCClient<S> {
+    #[inline]
+    #[must_use]
+    pub const fn new(pid: u64) -> Self {
+        Self {
+            pid,
+            __: core::marker::PhantomData,
+        }
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn pid(&self) -> u64 {
+        self.pid
+    }
+
+    /// Sends a request without waiting for, or expecting, a reply.
+    pub unsafe fn notify(&self, req: S::Request) -> Result<(), SkyError> {
+        send_packet(self.pid, &RPCPacket::new(RPCKind::Notification, 0, req))
+    }
+
+    /// Sends a request and waits for its reply.
+    /// Unrelated messages received in the meantime are left for [`Message::recv`].
+    pub unsafe fn call(&self, req: S::Request) -> Result<S::Response, SkyError> {
+        let seq = NEXT_SEQ.fetch_add(1, Ordering::Relaxed);
+        send_packet(self.pid, &RPCPacket::new(RPCKind::Request, seq, req))?;
+
+        let msg = Message::recv_matching(|msg| RPCHeader::is_reply(msg, self.pid, seq));
+        Ok(RPCPacket::<S::Response>::from_bytes(msg.data)
+            .expect("Malformed RPC reply")
+            .body)
+    }
+}
+
+/// What [`RPCServer::handle`] made of a message.
+#[derive(Debug)]
+pub enum RPCHandled<T> {
+    /// The message is not a request or notification for the service.
+    Ignored,
+    /// A notification was handled.
+    Notified,
+    /// A request was handled, and this reply is due.
+    Reply(RPCPacket<T>),
+}
+
+pub struct RPCServer<S: RPCService> {
+    __: core::marker::PhantomData<S>,
+}
+
+impl<S: RPCService> Default for RPCServer<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S: RPCService> RPCServer<S> {
+    #[inline]
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            __: core::marker::PhantomData,
+        }
+    }
+
+    /// Passes `msg` to `handler` if it is a request or notification for this service.
+    pub fn handle(
+        &self,
+        msg: &Message,
+        handler: &mut impl FnMut(u64, S::Request) -> S::Response,
+    ) -> RPCHandled<S::Response> {
+        if msg.pid == 0 {
+            return RPCHandled::Ignored;
+        }
+
+        let Some(packet) = RPCPacket::<S::Request>::from_bytes(msg.data) else {
+            return RPCHandled::Ignored;
+        };
+
+        match packet.header.kind {
+            RPCKind::Request => {
+                let resp = handler(msg.pid, packet.body);
+                RPCHandled::Reply(RPCPacket::new(RPCKind::Reply, packet.header.seq, resp))
+            }
+            RPCKind::Notification => {
+                handler(msg.pid, packet.body);
+                RPCHandled::Notified
+            }
+            RPCKind::Reply => RPCHandled::Ignored,
+        }
+    }
+}
+
+#[cfg(feature = "userspace")]
+impl<S: RPCService> RPCServer<S> {
+    /// Handles `msg` if it is a request or notification for this service.
+    /// The message is handed back untouched otherwise.
+    pub unsafe fn dispatch(
+        &self,
+        msg: Message,
+        handler: &mut impl FnMut(u64, S::Request) -> S::Response,
+    ) -> Result<(), Message> {
+        match self.handle(&msg, handler) {
+            RPCHandled::Ignored => return Err(msg),
+            RPCHandled::Notified => {}
+            RPCHandled::Reply(packet) => {
+                // The client may have exited in the meantime; nobody is left to tell.
+                let _ = send_packet(msg.pid, &packet);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Dispatches incoming requests forever, dropping any message that isn't one.
+    pub unsafe fn serve(&self, mut handler: impl FnMut(u64, S::Request) -> S::Response) -> ! {
+        loop {
+            let _ = self.dispatch(Message::recv(), &mut handler);
+        }
+    }
+}
@@ -0,0 +1,57 @@
+// Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use crate::{
+    msg::{KernelMessage, Message},
+    syscall::{syscall, SystemCall},
+    SkyError,
+};
+
+unsafe fn name_syscall(call: SystemCall, name: &str) -> Result<u64, SkyError> {
+    let ret: u64;
+    let err = syscall!(
+        call,
+        in("rsi") name.as_ptr() as u64,
+        in("rdx") name.len() as u64,
+        lateout("rax") ret,
+    );
+    SkyError::from_raw(err).map(|()| ret)
+}
+
+/// Publishes `name` as belonging to the calling process.
+/// Names are dropped automatically when the process exits.
+pub unsafe fn register(name: &str) -> Result<(), SkyError> {
+    name_syscall(SystemCall::RegisterService, name).map(drop)
+}
+
+pub unsafe fn unregister(name: &str) -> Result<(), SkyError> {
+    name_syscall(SystemCall::UnregisterService, name).map(drop)
+}
+
+/// Returns the PID owning `name`, usable as a [`Message`] target.
+#[must_use]
+pub unsafe fn lookup(name: &str) -> Option<u64> {
+    name_syscall(SystemCall::LookupService, name).ok()
+}
+
+/// Subscribes to [`KernelMessage::ServiceRegistered`] and [`KernelMessage::ServiceUnregistered`]
+/// for `name`. If the name is already registered, a notification is queued right away.
+pub unsafe fn watch(name: &str) {
+    name_syscall(SystemCall::WatchService, name).unwrap();
+}
+
+/// Blocks until `name` is registered and returns its owner.
+#[must_use]
+pub unsafe fn wait_for(name: &str) -> u64 {
+    watch(name);
+    let msg = Message::recv_matching(|msg| {
+        msg.pid == 0
+            && matches!(
+                postcard::from_bytes(msg.data),
+                Ok(KernelMessage::ServiceRegistered(v, _)) if v == name
+            )
+    });
+    let Ok(KernelMessage::ServiceRegistered(_, pid)) = postcard::from_bytes(msg.data) else {
+        unreachable!();
+    };
+    pid
+}
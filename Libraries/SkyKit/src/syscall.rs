@@ -2,6 +2,9 @@
 
 use num_enum::TryFromPrimitive;
 
+#[cfg(feature = "userspace")]
+use crate::SkyError;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
 #[repr(u64)]
 pub enum AccessSize {
@@ -10,6 +13,20 @@ pub enum AccessSize {
     DWord,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u64)]
+pub enum MmioCaching {
+    Uncacheable,
+    WriteCombining,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u64)]
+pub enum TlsRegister {
+    Fs,
+    Gs,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
 #[repr(u64)]
 pub enum SystemCall {
@@ -27,24 +44,79 @@ pub enum SystemCall {
     NewOSDTEntry,
     GetOSDTEntryInfo,
     SetOSDTEntryProp,
+    MsgRecvFiltered,
+    RegisterService,
+    UnregisterService,
+    LookupService,
+    WatchService,
+    ShmCreate,
+    ShmGrant,
+    ShmRevoke,
+    ShmMap,
+    ShmUnmap,
+    GrantCapabilities,
+    PortGrantDirect,
+    MmioMap,
+    MmioUnmap,
+    DmaAllocate,
+    ThreadSpawn,
+    ThreadExit,
+    ThreadJoin,
+    ThreadDetach,
+    FutexWait,
+    FutexWake,
+    ClockMonotonic,
+    SleepUntil,
+    TimerCreate,
+    TimerCancel,
+    SpawnExtension,
+    WatchProcess,
+    SetTlsBase,
+    SetPriority,
 }
 
+/// Issues system call `$call` with the given extra operands and evaluates to the raw error code
+/// from `r9`. The registers the kernel always writes are declared here, so no wrapper can leave
+/// the compiler thinking they survive the call.
+#[cfg(feature = "userspace")]
+macro_rules! syscall {
+    ($call:expr $(, $($operands:tt)*)?) => {{
+        let err: u64;
+        core::arch::asm!(
+            "syscall",
+            in("rdi") $call as u64,
+            lateout("r9") err,
+            lateout("rcx") _,
+            lateout("r11") _,
+            options(nostack),
+            $($($operands)*)?
+        );
+        err
+    }};
+}
+#[cfg(feature = "userspace")]
+pub(crate) use syscall;
+
 #[cfg(feature = "userspace")]
 impl SystemCall {
-    pub unsafe fn quit() -> ! {
-        core::arch::asm!("int 249", in("rdi") Self::Quit as u64, options(nostack, noreturn));
+    pub unsafe fn quit(code: u64) -> ! {
+        core::arch::asm!(
+            "syscall",
+            in("rdi") Self::Quit as u64,
+            in("rsi") code,
+            options(nostack, noreturn),
+        );
     }
 
     pub unsafe fn r#yield() {
-        core::arch::asm!("int 249", in("rdi") Self::Yield as u64, options(nostack));
+        syscall!(Self::Yield);
     }
 
-    pub unsafe fn register_irq_handler(irq: u8) {
-        core::arch::asm!(
-            "int 249",
-            in("rdi") Self::RegisterIRQ as u64,
+    pub unsafe fn register_irq_handler(irq: u8) -> Result<(), SkyError> {
+        let err = syscall!(
+            Self::RegisterIRQ,
             in("sil") irq,
-            options(nostack),
         );
+        SkyError::from_raw(err)
     }
 }
@@ -1,6 +1,6 @@
 // Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
 
-use crate::syscall::SystemCall;
+use crate::syscall::{syscall, SystemCall};
 
 #[global_allocator]
 static GLOBAL_ALLOCATOR: Allocator = Allocator;
@@ -10,13 +10,14 @@ struct Allocator;
 unsafe impl core::alloc::GlobalAlloc for Allocator {
     unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
         let mut ptr: u64;
-        core::arch::asm!(
-            "int 249",
-            in("rdi") SystemCall::Allocate as u64,
+        let err = syscall!(
+            SystemCall::Allocate,
             in("rsi") layout.pad_to_align().size() as u64,
             out("rax") ptr,
-            options(nostack),
         );
+        if err != 0 {
+            return core::ptr::null_mut();
+        }
         ptr as *mut u8
     }
 
@@ -25,12 +26,10 @@ unsafe impl core::alloc::GlobalAlloc for Allocator {
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
-        core::arch::asm!(
-            "int 249",
-            in("rdi") SystemCall::Free as u64,
+        syscall!(
+            SystemCall::Free,
             in("rsi") ptr as u64,
             in("rdx") layout.pad_to_align().size() as u64,
-            options(nostack),
         );
     }
 }
@@ -0,0 +1,63 @@
+// Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use crate::{
+    syscall::{syscall, SystemCall},
+    SkyError,
+};
+
+/// Zero-filled, physically contiguous buffer for programming device DMA.
+/// The pages never move, so the physical address stays valid until the buffer is freed.
+#[derive(Debug, PartialEq, Eq)]
+pub struct DmaBuffer {
+    addr: u64,
+    phys: u64,
+    size: u64,
+}
+
+impl DmaBuffer {
+    /// `align` is in bytes and must be a power of two; anything below a page means page-aligned.
+    /// `below_4g` restricts the buffer to 32-bit physical addresses.
+    pub unsafe fn new(size: u64, align: u64, below_4g: bool) -> Result<Self, SkyError> {
+        let (addr, phys): (u64, u64);
+        let err = syscall!(
+            SystemCall::DmaAllocate,
+            in("rsi") size,
+            inout("rdx") align => phys,
+            in("r10") u64::from(below_4g),
+            lateout("rax") addr,
+        );
+        SkyError::from_raw(err).map(|()| Self { addr, phys, size })
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn addr(&self) -> u64 {
+        self.addr
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn phys(&self) -> u64 {
+        self.phys
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn size(&self) -> u64 {
+        self.size
+    }
+
+    #[inline]
+    #[must_use]
+    pub unsafe fn as_mut_slice(&self) -> &'static mut [u8] {
+        core::slice::from_raw_parts_mut(self.addr as *mut u8, self.size as _)
+    }
+
+    pub unsafe fn free(self) {
+        syscall!(
+            SystemCall::Free,
+            in("rsi") self.addr,
+            in("rdx") self.size,
+        );
+    }
+}
@@ -2,7 +2,7 @@
 
 use core::fmt::Write;
 
-use crate::syscall::SystemCall;
+use crate::syscall::{syscall, SystemCall};
 
 pub struct KWriter;
 
@@ -13,12 +13,10 @@ impl Write for KWriter {
         }
 
         unsafe {
-            core::arch::asm!(
-                "int 249",
-                in("rdi") SystemCall::KPrint as u64,
+            syscall!(
+                SystemCall::KPrint,
                 in("rsi") s.as_ptr() as u64,
                 in("rdx") s.len() as u64,
-                options(nostack),
             );
         }
         Ok(())
@@ -0,0 +1,59 @@
+// Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use crate::{
+    syscall::{syscall, MmioCaching, SystemCall},
+    SkyError,
+};
+
+/// Device registers mapped into the calling process, e.g. a PCI BAR.
+/// The physical range must be within the process' capabilities.
+#[derive(Debug, PartialEq, Eq)]
+pub struct MmioRegion {
+    addr: u64,
+    size: u64,
+}
+
+impl MmioRegion {
+    pub unsafe fn map(phys: u64, size: u64, caching: MmioCaching) -> Result<Self, SkyError> {
+        let addr: u64;
+        let err = syscall!(
+            SystemCall::MmioMap,
+            in("rsi") phys,
+            in("rdx") size,
+            in("r10") caching as u64,
+            lateout("rax") addr,
+        );
+        SkyError::from_raw(err).map(|()| Self { addr, size })
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn addr(&self) -> u64 {
+        self.addr
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn size(&self) -> u64 {
+        self.size
+    }
+
+    #[must_use]
+    pub unsafe fn read<T: Copy>(&self, off: u64) -> T {
+        assert!(off + core::mem::size_of::<T>() as u64 <= self.size);
+        core::ptr::read_volatile((self.addr + off) as *const T)
+    }
+
+    pub unsafe fn write<T: Copy>(&self, off: u64, value: T) {
+        assert!(off + core::mem::size_of::<T>() as u64 <= self.size);
+        core::ptr::write_volatile((self.addr + off) as *mut T, value);
+    }
+
+    pub unsafe fn unmap(self) -> Result<(), SkyError> {
+        let err = syscall!(
+            SystemCall::MmioUnmap,
+            in("rsi") self.addr,
+        );
+        SkyError::from_raw(err)
+    }
+}
@@ -1,6 +1,12 @@
 // Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
 
 mod allocator;
+pub mod dma;
 pub mod logger;
+pub mod mmio;
 mod panic;
 pub mod port;
+pub mod shm;
+pub mod sync;
+pub mod thread;
+pub mod time;
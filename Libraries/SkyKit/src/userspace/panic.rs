@@ -5,5 +5,5 @@ use crate::syscall::SystemCall;
 #[panic_handler]
 fn panic_handler(info: &core::panic::PanicInfo) -> ! {
     error!("{info}");
-    unsafe { SystemCall::quit() }
+    unsafe { SystemCall::quit(u64::MAX) }
 }
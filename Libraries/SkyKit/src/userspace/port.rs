@@ -1,17 +1,46 @@
 // Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
 
-use crate::syscall::{AccessSize, SystemCall};
+use alloc::vec::Vec;
+use core::cell::SyncUnsafeCell;
+
+use crate::{
+    syscall::{syscall, AccessSize, SystemCall},
+    SkyError,
+};
+
+/// Inclusive port ranges the kernel lets this process access with `in`/`out` directly.
+static DIRECT_RANGES: SyncUnsafeCell<Vec<(u16, u16)>> = SyncUnsafeCell::new(Vec::new());
+
+/// Asks the kernel for direct access to `first..=last`, which must be within the process' capabilities.
+/// Afterwards, [`Port`] skips the system call for accesses within the range.
+pub unsafe fn grant_direct(first: u16, last: u16) -> Result<(), SkyError> {
+    let err = syscall!(
+        SystemCall::PortGrantDirect,
+        in("rsi") first,
+        in("rdx") last,
+    );
+    SkyError::from_raw(err)?;
+    (*DIRECT_RANGES.get()).push((first, last));
+    Ok(())
+}
+
+fn is_direct<T>(port: u16) -> bool {
+    let Some(last) = port.checked_add(core::mem::size_of::<T>() as u16 - 1) else {
+        return false;
+    };
+    unsafe { &*DIRECT_RANGES.get() }
+        .iter()
+        .any(|&(start, end)| start <= port && last <= end)
+}
 
 macro_rules! PortIOSystemCallIn {
     ($out:tt, $port:expr, $size:expr) => {{
         let mut val: Self;
-        core::arch::asm!(
-            "int 249",
-            in("rdi") SystemCall::PortIn as u64,
+        syscall!(
+            SystemCall::PortIn,
             in("rsi") $port,
             in("rdx") $size as u64,
             out($out) val,
-            options(nostack),
         );
         val
     }};
@@ -19,13 +48,35 @@ macro_rules! PortIOSystemCallIn {
 
 macro_rules! PortIOSystemCallOut {
     ($in_:tt, $port:expr, $value:expr, $size:expr) => {
-        core::arch::asm!(
-            "int 249",
-            in("rdi") SystemCall::PortOut as u64,
+        syscall!(
+            SystemCall::PortOut,
             in("rsi") $port,
             in("rdx") $size as u64,
             in($in_) $value,
-            options(nostack),
+        )
+    };
+}
+
+macro_rules! PortIODirectIn {
+    ($out:tt, $port:expr) => {{
+        let val: Self;
+        core::arch::asm!(
+            concat!("in ", $out, ", dx"),
+            in("dx") $port,
+            out($out) val,
+            options(nomem, nostack, preserves_flags),
+        );
+        val
+    }};
+}
+
+macro_rules! PortIODirectOut {
+    ($in_:tt, $port:expr, $value:expr) => {
+        core::arch::asm!(
+            concat!("out dx, ", $in_),
+            in("dx") $port,
+            in($in_) $value,
+            options(nomem, nostack, preserves_flags),
         )
     };
 }
@@ -37,31 +88,52 @@ pub trait PortIO: Sized {
 
 impl PortIO for u8 {
     unsafe fn read(port: u16) -> Self {
+        if is_direct::<Self>(port) {
+            return PortIODirectIn!("al", port);
+        }
         PortIOSystemCallIn!("al", port, AccessSize::Byte)
     }
 
     unsafe fn write(port: u16, value: Self) {
-        PortIOSystemCallOut!("cl", port, value, AccessSize::Byte);
+        if is_direct::<Self>(port) {
+            PortIODirectOut!("al", port, value);
+            return;
+        }
+        PortIOSystemCallOut!("r10b", port, value, AccessSize::Byte);
     }
 }
 
 impl PortIO for u16 {
     unsafe fn read(port: u16) -> Self {
+        if is_direct::<Self>(port) {
+            return PortIODirectIn!("ax", port);
+        }
         PortIOSystemCallIn!("ax", port, AccessSize::Word)
     }
 
     unsafe fn write(port: u16, value: Self) {
-        PortIOSystemCallOut!("cx", port, value, AccessSize::Word);
+        if is_direct::<Self>(port) {
+            PortIODirectOut!("ax", port, value);
+            return;
+        }
+        PortIOSystemCallOut!("r10w", port, value, AccessSize::Word);
     }
 }
 
 impl PortIO for u32 {
     unsafe fn read(port: u16) -> Self {
+        if is_direct::<Self>(port) {
+            return PortIODirectIn!("eax", port);
+        }
         PortIOSystemCallIn!("eax", port, AccessSize::DWord)
     }
 
     unsafe fn write(port: u16, value: Self) {
-        PortIOSystemCallOut!("ecx", port, value, AccessSize::DWord);
+        if is_direct::<Self>(port) {
+            PortIODirectOut!("eax", port, value);
+            return;
+        }
+        PortIOSystemCallOut!("r10d", port, value, AccessSize::DWord);
     }
 }
 
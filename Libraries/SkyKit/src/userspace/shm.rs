@@ -0,0 +1,80 @@
+// Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use crate::{
+    syscall::{syscall, SystemCall},
+    SkyError,
+};
+
+/// Handle to a shared memory object.
+/// The object lives until its creator exits; grantees lose their mapping at that point.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SharedMemory {
+    id: u64,
+}
+
+impl SharedMemory {
+    /// Creates a zero-filled object of at least `size` bytes, owned by the calling process.
+    pub unsafe fn new(size: u64) -> Result<Self, SkyError> {
+        let id: u64;
+        let err = syscall!(
+            SystemCall::ShmCreate,
+            in("rsi") size,
+            lateout("rax") id,
+        );
+        SkyError::from_raw(err).map(|()| Self { id })
+    }
+
+    /// Wraps an ID received from the owner, e.g. through a message.
+    #[inline]
+    #[must_use]
+    pub const fn from_id(id: u64) -> Self {
+        Self { id }
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Allows `pid` to map the object. Granting again updates the rights in place.
+    pub unsafe fn grant(&self, pid: u64, writable: bool) -> Result<(), SkyError> {
+        let err = syscall!(
+            SystemCall::ShmGrant,
+            in("rsi") self.id,
+            in("rdx") pid,
+            in("r10") u64::from(writable),
+        );
+        SkyError::from_raw(err)
+    }
+
+    /// Withdraws access from `pid`, unmapping the object from it if needed.
+    pub unsafe fn revoke(&self, pid: u64) -> Result<(), SkyError> {
+        let err = syscall!(
+            SystemCall::ShmRevoke,
+            in("rsi") self.id,
+            in("rdx") pid,
+        );
+        SkyError::from_raw(err)
+    }
+
+    pub unsafe fn map(&self) -> Result<&'static mut [u8], SkyError> {
+        let (addr, size): (u64, u64);
+        let err = syscall!(
+            SystemCall::ShmMap,
+            in("rsi") self.id,
+            lateout("rax") addr,
+            lateout("rdx") size,
+        );
+        SkyError::from_raw(err)
+            .map(|()| core::slice::from_raw_parts_mut(addr as *mut u8, size as _))
+    }
+
+    pub unsafe fn unmap(&self) -> Result<(), SkyError> {
+        let err = syscall!(
+            SystemCall::ShmUnmap,
+            in("rsi") self.id,
+        );
+        SkyError::from_raw(err)
+    }
+}
@@ -0,0 +1,52 @@
+// Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use super::MutexGuard;
+
+#[derive(Debug, Default)]
+pub struct Condvar {
+    /// Bumped on every notification, so a wait started before it does not sleep.
+    seq: AtomicU32,
+}
+
+impl Condvar {
+    #[inline]
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            seq: AtomicU32::new(0),
+        }
+    }
+
+    /// Releases the lock while sleeping. May return spuriously.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let seq = self.seq.load(Ordering::Relaxed);
+        let mutex = guard.mutex;
+        drop(guard);
+        super::futex_wait(&self.seq, seq);
+        mutex.lock_contended();
+        MutexGuard { mutex }
+    }
+
+    pub fn wait_while<'a, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    pub fn notify_one(&self) {
+        self.seq.fetch_add(1, Ordering::Relaxed);
+        super::futex_wake(&self.seq, 1);
+    }
+
+    pub fn notify_all(&self) {
+        self.seq.fetch_add(1, Ordering::Relaxed);
+        super::futex_wake(&self.seq, u64::MAX);
+    }
+}
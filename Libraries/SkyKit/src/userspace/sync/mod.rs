@@ -0,0 +1,40 @@
+// Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use core::sync::atomic::AtomicU32;
+
+use crate::syscall::{syscall, SystemCall};
+
+mod condvar;
+mod mutex;
+mod once;
+
+pub use condvar::Condvar;
+pub use mutex::{Mutex, MutexGuard};
+pub use once::Once;
+
+/// Sleeps until woken through `futex`, unless it no longer holds `expected`.
+/// May return spuriously, so callers must re-check their condition.
+pub fn futex_wait(futex: &AtomicU32, expected: u32) {
+    unsafe {
+        syscall!(
+            SystemCall::FutexWait,
+            in("rsi") futex.as_ptr() as u64,
+            in("rdx") u64::from(expected),
+        );
+    }
+}
+
+/// Wakes up to `count` threads sleeping on `futex`, returning how many were woken.
+/// Works across processes if `futex` is in shared memory.
+pub fn futex_wake(futex: &AtomicU32, count: u64) -> u64 {
+    let woken: u64;
+    unsafe {
+        syscall!(
+            SystemCall::FutexWake,
+            in("rsi") futex.as_ptr() as u64,
+            in("rdx") count,
+            lateout("rax") woken,
+        );
+    }
+    woken
+}
@@ -0,0 +1,46 @@
+// Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+const INCOMPLETE: u32 = 0;
+const RUNNING: u32 = 1;
+const COMPLETE: u32 = 2;
+
+#[derive(Debug, Default)]
+pub struct Once {
+    state: AtomicU32,
+}
+
+impl Once {
+    #[inline]
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU32::new(INCOMPLETE),
+        }
+    }
+
+    #[inline]
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+
+    /// Runs `f` if no other call did; blocks while another thread is running it.
+    pub fn call_once(&self, f: impl FnOnce()) {
+        match self
+            .state
+            .compare_exchange(INCOMPLETE, RUNNING, Ordering::Acquire, Ordering::Acquire)
+        {
+            Ok(_) => {
+                f();
+                self.state.store(COMPLETE, Ordering::Release);
+                super::futex_wake(&self.state, u64::MAX);
+            }
+            Err(_) => {
+                while self.state.load(Ordering::Acquire) == RUNNING {
+                    super::futex_wait(&self.state, RUNNING);
+                }
+            }
+        }
+    }
+}
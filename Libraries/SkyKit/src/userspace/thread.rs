@@ -0,0 +1,133 @@
+// Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use alloc::{boxed::Box, sync::Arc};
+use core::{cell::UnsafeCell, mem::ManuallyDrop};
+
+use crate::{
+    syscall::{syscall, SystemCall, TlsRegister},
+    SkyError,
+};
+
+type Entry = Box<dyn FnOnce() -> u64 + Send>;
+
+/// Where a thread leaves its result. Shared with the handle, so whichever of the two goes last
+/// frees it.
+struct Packet<T>(UnsafeCell<Option<T>>);
+
+// Only the thread writes the result, and only the joiner reads it once the thread is gone.
+unsafe impl<T: Send> Sync for Packet<T> {}
+
+/// Handle to a thread started with [`spawn`]. Dropping it detaches the thread, which keeps
+/// running, and its ID and result are released once it exits.
+pub struct JoinHandle<T> {
+    tid: u64,
+    packet: Arc<Packet<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    #[inline]
+    #[must_use]
+    pub const fn tid(&self) -> u64 {
+        self.tid
+    }
+
+    /// Blocks until the thread returns, yielding its result.
+    pub fn join(self) -> Result<T, SkyError> {
+        // Joining releases the ID, so the handle must not detach afterwards.
+        let this = ManuallyDrop::new(self);
+        let packet = unsafe { core::ptr::read(&this.packet) };
+        let err = unsafe {
+            syscall!(
+                SystemCall::ThreadJoin,
+                in("rsi") this.tid,
+                lateout("rax") _,
+            )
+        };
+        SkyError::from_raw(err)?;
+        Ok(unsafe { (*packet.0.get()).take() }.unwrap())
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        unsafe {
+            syscall!(
+                SystemCall::ThreadDetach,
+                in("rsi") self.tid,
+            );
+        }
+    }
+}
+
+impl<T> core::fmt::Debug for JoinHandle<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("JoinHandle")
+            .field("tid", &self.tid)
+            .finish()
+    }
+}
+
+extern "C" fn thread_start(entry: *mut Entry) -> ! {
+    let entry = unsafe { Box::from_raw(entry) };
+    unsafe { exit(entry()) }
+}
+
+/// Runs `f` on a new thread of the calling process, with its own stack.
+/// The kernel also gives it a fresh copy of the `#[thread_local]` statics.
+pub fn spawn<T: Send + 'static, F: FnOnce() -> T + Send + 'static>(
+    f: F,
+) -> Result<JoinHandle<T>, SkyError> {
+    let packet = Arc::new(Packet(UnsafeCell::new(None)));
+    let theirs = packet.clone();
+    let entry: Entry = Box::new(move || {
+        unsafe { *theirs.0.get() = Some(f()) };
+        0
+    });
+    let arg = Box::into_raw(Box::new(entry));
+    let tid: u64;
+    let err = unsafe {
+        syscall!(
+            SystemCall::ThreadSpawn,
+            in("rsi") thread_start as usize as u64,
+            in("rdx") arg as u64,
+            lateout("rax") tid,
+        )
+    };
+    if let Err(e) = SkyError::from_raw(err) {
+        drop(unsafe { Box::from_raw(arg) });
+        return Err(e);
+    }
+    Ok(JoinHandle { tid, packet })
+}
+
+/// Ends the calling thread. The process keeps running as long as it has other threads.
+pub unsafe fn exit(value: u64) -> ! {
+    core::arch::asm!(
+        "syscall",
+        in("rdi") SystemCall::ThreadExit as u64,
+        in("rsi") value,
+        options(nostack, noreturn),
+    );
+}
+
+/// Points the `FS` or `GS` segment of the calling thread at `addr`.
+/// `FS` already holds the thread's `#[thread_local]` block, so replacing it breaks those statics.
+pub unsafe fn set_tls_base(reg: TlsRegister, addr: u64) -> Result<(), SkyError> {
+    let err = syscall!(
+        SystemCall::SetTlsBase,
+        in("rsi") reg as u64,
+        in("rdx") addr,
+    );
+    SkyError::from_raw(err)
+}
+
+/// Changes the priority of a thread of the calling process, or the calling thread if `tid` is 0.
+/// Real-time priorities require an IRQ capability; see [`crate::priority`].
+pub unsafe fn set_priority(tid: u64, priority: u8) -> Result<(), SkyError> {
+    let err = syscall!(
+        SystemCall::SetPriority,
+        in("rsi") tid,
+        in("rdx") u64::from(priority),
+    );
+    SkyError::from_raw(err)
+}
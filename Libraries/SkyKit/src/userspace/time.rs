@@ -0,0 +1,84 @@
+// Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use core::time::Duration;
+
+use crate::{
+    syscall::{syscall, SystemCall},
+    SkyError,
+};
+
+/// Nanoseconds on a clock that never goes backwards, starting around boot.
+#[must_use]
+pub fn now_ns() -> u64 {
+    let ns: u64;
+    unsafe {
+        syscall!(
+            SystemCall::ClockMonotonic,
+            lateout("rax") ns,
+        );
+    }
+    ns
+}
+
+/// Suspends the calling thread until [`now_ns`] reaches `deadline_ns`.
+pub fn sleep_until(deadline_ns: u64) {
+    unsafe {
+        syscall!(
+            SystemCall::SleepUntil,
+            in("rsi") deadline_ns,
+        );
+    }
+}
+
+pub fn sleep(duration: Duration) {
+    sleep_until(deadline_after(duration));
+}
+
+#[must_use]
+pub fn deadline_after(duration: Duration) -> u64 {
+    now_ns().saturating_add(duration.as_nanos().try_into().unwrap_or(u64::MAX))
+}
+
+/// Delivers [`crate::msg::KernelMessage::TimerFired`] with its ID to the process.
+/// It is destroyed once fired, unless periodic.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Timer {
+    id: u64,
+}
+
+impl Timer {
+    unsafe fn create(deadline_ns: u64, period_ns: u64) -> Result<Self, SkyError> {
+        let id: u64;
+        let err = syscall!(
+            SystemCall::TimerCreate,
+            in("rsi") deadline_ns,
+            in("rdx") period_ns,
+            lateout("rax") id,
+        );
+        SkyError::from_raw(err).map(|()| Self { id })
+    }
+
+    pub unsafe fn oneshot(deadline_ns: u64) -> Result<Self, SkyError> {
+        Self::create(deadline_ns, 0)
+    }
+
+    /// Fires first at `deadline_ns`, then every `period`, which must be at least a millisecond.
+    pub unsafe fn periodic(deadline_ns: u64, period: Duration) -> Result<Self, SkyError> {
+        let period = period.as_nanos().try_into();
+        Self::create(deadline_ns, period.map_err(|_| SkyError::InvalidArgument)?)
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub unsafe fn cancel(self) -> Result<(), SkyError> {
+        let err = syscall!(
+            SystemCall::TimerCancel,
+            in("rsi") self.id,
+        );
+        SkyError::from_raw(err)
+    }
+}
@@ -0,0 +1,120 @@
+// Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+#![deny(warnings, clippy::nursery, unused_extern_crates)]
+
+use skykit::{
+    msg::{Mailbox, Message},
+    rpc::{RPCHandled, RPCHeader, RPCKind, RPCPacket, RPCServer, RPCService},
+};
+
+struct Doubler;
+
+impl RPCService for Doubler {
+    type Request = u32;
+    type Response = u32;
+}
+
+fn packet(id: u64, pid: u64, kind: RPCKind, seq: u64, body: u32) -> Message {
+    Message::new(id, pid, RPCPacket::new(kind, seq, body).to_vec().leak())
+}
+
+#[test]
+fn test_peek_header() {
+    let data = RPCPacket::new(RPCKind::Reply, 0x1234, (1u8, 0xDEAD_BEEFu32)).to_vec();
+    assert_eq!(
+        RPCHeader::peek(&data),
+        Some(RPCHeader::new(RPCKind::Reply, 0x1234))
+    );
+}
+
+#[test]
+fn test_roundtrip() {
+    let data = RPCPacket::new(RPCKind::Request, 7, 0xCAFEu16).to_vec();
+    let packet = RPCPacket::<u16>::from_bytes(&data).unwrap();
+    assert_eq!(packet.header, RPCHeader::new(RPCKind::Request, 7));
+    assert_eq!(packet.body, 0xCAFE);
+}
+
+#[test]
+fn test_reject_garbage() {
+    assert_eq!(RPCHeader::peek(&[]), None);
+    assert!(RPCPacket::<u16>::from_bytes(&[0xFF, 0xFF]).is_none());
+}
+
+#[test]
+fn test_set_aside_unrelated() {
+    let mut mailbox = Mailbox::new();
+    let is_reply = |msg: &Message| RPCHeader::is_reply(msg, 5, 3);
+
+    let incoming = [
+        packet(1, 5, RPCKind::Notification, 0, 1),
+        Message::new(2, 0, &[0xFF]),
+        packet(3, 6, RPCKind::Reply, 3, 2),
+        packet(4, 5, RPCKind::Reply, 3, 3),
+    ];
+    let mut received = incoming
+        .into_iter()
+        .filter_map(|v| mailbox.sort(v, is_reply));
+    assert_eq!(received.next().map(|v| v.id), Some(4));
+
+    let rest: Vec<_> = core::iter::from_fn(|| mailbox.take(|_| true))
+        .map(|v| v.id)
+        .collect();
+    assert_eq!(rest, [1, 2, 3]);
+}
+
+#[test]
+fn test_out_of_order_replies() {
+    let mut mailbox = Mailbox::new();
+
+    assert!(mailbox
+        .sort(packet(1, 5, RPCKind::Reply, 2, 20), |v| {
+            RPCHeader::is_reply(v, 5, 1)
+        })
+        .is_none());
+    let first = mailbox
+        .sort(packet(2, 5, RPCKind::Reply, 1, 10), |v| {
+            RPCHeader::is_reply(v, 5, 1)
+        })
+        .unwrap();
+    assert_eq!(first.id, 2);
+
+    // The second reply is already there, no need to receive anything.
+    let second = mailbox.take(|v| RPCHeader::is_reply(v, 5, 2)).unwrap();
+    assert_eq!(RPCPacket::<u32>::from_bytes(second.data).unwrap().body, 20);
+    assert!(mailbox.take(|_| true).is_none());
+}
+
+#[test]
+fn test_server_handle() {
+    let server = RPCServer::<Doubler>::new();
+    let mut seen = vec![];
+    let mut handler = |pid, v: u32| {
+        seen.push((pid, v));
+        v * 2
+    };
+
+    let RPCHandled::Reply(reply) =
+        server.handle(&packet(1, 4, RPCKind::Request, 9, 21), &mut handler)
+    else {
+        panic!("No reply to a request");
+    };
+    assert_eq!(reply.header, RPCHeader::new(RPCKind::Reply, 9));
+    assert_eq!(reply.body, 42);
+
+    assert!(matches!(
+        server.handle(&packet(2, 4, RPCKind::Notification, 0, 5), &mut handler),
+        RPCHandled::Notified
+    ));
+    for msg in [
+        packet(3, 4, RPCKind::Reply, 9, 1),
+        packet(4, 0, RPCKind::Request, 1, 1),
+        Message::new(5, 4, &[0xFF, 0xFF]),
+    ] {
+        assert!(matches!(
+            server.handle(&msg, &mut handler),
+            RPCHandled::Ignored
+        ));
+    }
+    assert_eq!(seen, [(4, 21), (4, 5)]);
+}
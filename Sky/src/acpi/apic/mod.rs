@@ -1,7 +1,8 @@
 // Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
 
 use amd64::{
-    msr::{apic::APICBase, ModelSpecificReg},
+    cpuid::CPUIdentification,
+    msr::{apic::APICBase, tsc_deadline::TscDeadline, ModelSpecificReg},
     paging::PageTableFlags,
 };
 use num_enum::IntoPrimitive;
@@ -12,6 +13,14 @@ pub mod lvt;
 
 pub struct LocalAPIC {
     addr: u64,
+    timer: Option<TimerSource>,
+}
+
+/// What the LAPIC timer counts, with the rate measured by [`LocalAPIC::setup_timer`].
+#[derive(Debug, Clone, Copy)]
+pub enum TimerSource {
+    TscDeadline { per_ms: u64 },
+    OneShot { per_ms: u64 },
 }
 
 #[derive(Debug, IntoPrimitive)]
@@ -175,7 +184,7 @@ pub struct LocalAPICVer {
 impl LocalAPIC {
     #[inline]
     pub const fn new(addr: u64) -> Self {
-        Self { addr }
+        Self { addr, timer: None }
     }
 
     pub fn write_reg<T: Into<u64>, V: Into<u32>>(&self, reg: T, value: V) {
@@ -253,23 +262,141 @@ impl LocalAPIC {
         );
     }
 
-    pub fn setup_timer(&self, timer: &impl crate::timer::Timer) {
-        self.set_timer_divide(0x3);
-        self.set_timer_init_count(0xFFFF_FFFF);
+    pub fn id(&self) -> u8 {
+        (self.read_reg::<_, u32>(LocalAPICReg::ID) >> 24) as u8
+    }
+
+    /// Sends an IPI and waits for the LAPIC to accept it.
+    pub fn send_ipi(&self, cmd: InterruptCommand) {
+        let value = u64::from(cmd);
+        self.write_reg(LocalAPICReg::InterruptCommand2, (value >> 32) as u32);
+        self.write_reg(LocalAPICReg::InterruptCommand, value as u32);
+        while InterruptCommand::from(u64::from(
+            self.read_reg::<_, u32>(LocalAPICReg::InterruptCommand),
+        ))
+        .delivery_pending()
+        {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Programs the LAPIC of the calling CPU.
+    /// Only the BSP takes legacy PIC interrupts through LINT0.
+    pub fn init(&self, virtual_wire: bool) {
+        let ver = self.read_ver();
+
+        // Do not trust LAPIC to be empty at boot
+        if ver.max_lvt_entry() > 2 {
+            self.write_reg(
+                LocalAPICReg::LVTError,
+                lvt::LocalVectorTable::new().with_mask(true),
+            );
+        }
 
-        self.write_timer(self.read_timer().with_mask(false));
-        timer.sleep(10);
         self.write_timer(self.read_timer().with_mask(true));
+        self.write_lint(false, self.read_lint(false).with_mask(true));
+        self.write_lint(true, self.read_lint(true).with_mask(true));
+        if ver.max_lvt_entry() > 3 {
+            self.write_reg(
+                LocalAPICReg::LVTPerfCounter,
+                lvt::LocalVectorTable::new().with_mask(true),
+            );
+        }
+
+        if ver.max_lvt_entry() > 4 {
+            self.write_reg(
+                LocalAPICReg::LVTThermalSensor,
+                lvt::LocalVectorTable::new().with_mask(true),
+            );
+        }
+
+        self.enable();
+
+        // Set up virtual wire
+        if virtual_wire {
+            self.write_lint(
+                false,
+                lvt::LocalVectorTable::new().with_delivery_mode(DeliveryMode::ExtInt),
+            );
+        }
+        self.write_lint(
+            true,
+            lvt::LocalVectorTable::new().with_delivery_mode(DeliveryMode::Nmi),
+        );
+
+        if ver.max_lvt_entry() > 2 {
+            self.write_reg(
+                LocalAPICReg::LVTError,
+                lvt::LocalVectorTable::new().with_vector(0xFE),
+            );
+        }
+    }
+
+    /// Calibrates the timer against `timer` and sets it up in one-shot mode on the calling CPU,
+    /// counting in TSC cycles where the CPU supports TSC-deadline mode.
+    pub fn setup_timer(&mut self, timer: &impl crate::timer::Timer) {
+        if CPUIdentification::new().features.tsc_deadline() {
+            let start = unsafe { core::arch::x86_64::_rdtsc() };
+            timer.sleep(10);
+            let per_ms = (unsafe { core::arch::x86_64::_rdtsc() } - start) / 10;
+            debug!("Using TSC-deadline timer, {per_ms} cycles per ms");
+            self.timer = Some(TimerSource::TscDeadline { per_ms });
+        } else {
+            self.write_timer(lvt::TimerLVT::new().with_vector(128).with_mask(true));
+            self.set_timer_divide(0x3);
+            self.set_timer_init_count(0xFFFF_FFFF);
+
+            self.write_timer(self.read_timer().with_mask(false));
+            timer.sleep(10);
+            self.write_timer(self.read_timer().with_mask(true));
+
+            let per_ms = (0xFFFF_FFFF - self.read_timer_counter()) / 10;
+            debug!("Using one-shot LAPIC timer, {per_ms} ticks per ms");
+            self.timer = Some(TimerSource::OneShot {
+                per_ms: per_ms.into(),
+            });
+        }
+        self.init_timer();
+    }
 
-        let ticks_per_ms = (0xFFFF_FFFF - self.read_timer_counter()) / 10;
+    /// Puts the timer of the calling CPU in the mode picked by [`Self::setup_timer`], disarmed.
+    pub fn init_timer(&self) {
+        let mode = match self.timer.unwrap() {
+            TimerSource::TscDeadline { .. } => lvt::TimerMode::TscDeadline,
+            TimerSource::OneShot { .. } => lvt::TimerMode::OneShot,
+        };
         self.write_timer(
             lvt::TimerLVT::new()
                 .with_vector(128)
                 .with_mask(true)
-                .with_mode(lvt::TimerMode::Periodic),
+                .with_mode(mode),
         );
         self.set_timer_divide(0x3);
-        self.set_timer_init_count(ticks_per_ms);
+        self.disarm_timer();
+    }
+
+    /// Makes the timer of the calling CPU fire once, `delay_ns` from now.
+    pub fn arm_timer(&self, delay_ns: u64) {
+        match self.timer.unwrap() {
+            TimerSource::TscDeadline { per_ms } => unsafe {
+                let cycles = (u128::from(delay_ns) * u128::from(per_ms) / 1_000_000) as u64;
+                TscDeadline::new()
+                    .with_deadline(core::arch::x86_64::_rdtsc() + cycles.max(1))
+                    .write();
+            },
+            TimerSource::OneShot { per_ms } => {
+                let ticks = delay_ns.saturating_mul(per_ms) / 1_000_000;
+                // An initial count of 0 stops the timer instead.
+                self.set_timer_init_count(ticks.clamp(1, u32::MAX.into()) as u32);
+            }
+        }
+    }
+
+    pub fn disarm_timer(&self) {
+        match self.timer.unwrap() {
+            TimerSource::TscDeadline { .. } => unsafe { TscDeadline::new().write() },
+            TimerSource::OneShot { .. } => self.set_timer_init_count(0),
+        }
     }
 }
 
@@ -322,12 +449,7 @@ pub fn setup(state: &mut crate::system::state::SystemState) {
     let ver = lapic.read_ver();
     debug!("LAPIC version is {ver:#X?}");
 
-    // Do not trust LAPIC to be empty at boot
     if ver.max_lvt_entry() > 2 {
-        lapic.write_reg(
-            LocalAPICReg::LVTError,
-            lvt::LocalVectorTable::new().with_mask(true),
-        );
         crate::interrupts::idt::set_handler(
             0xFE,
             0,
@@ -337,26 +459,6 @@ pub fn setup(state: &mut crate::system::state::SystemState) {
             true,
         );
     }
-
-    lapic.write_timer(lapic.read_timer().with_mask(true));
-    lapic.write_lint(false, lapic.read_lint(false).with_mask(true));
-    lapic.write_lint(true, lapic.read_lint(true).with_mask(true));
-    if ver.max_lvt_entry() > 3 {
-        lapic.write_reg(
-            LocalAPICReg::LVTPerfCounter,
-            lvt::LocalVectorTable::new().with_mask(true),
-        );
-    }
-
-    if ver.max_lvt_entry() > 4 {
-        lapic.write_reg(
-            LocalAPICReg::LVTThermalSensor,
-            lvt::LocalVectorTable::new().with_mask(true),
-        );
-    }
-
-    lapic.enable();
-
     crate::interrupts::idt::set_handler(
         0xFD,
         0,
@@ -366,22 +468,6 @@ pub fn setup(state: &mut crate::system::state::SystemState) {
         true,
     );
 
-    // Set up virtual wire
-    lapic.write_lint(
-        false,
-        lvt::LocalVectorTable::new().with_delivery_mode(DeliveryMode::ExtInt),
-    );
-    lapic.write_lint(
-        true,
-        lvt::LocalVectorTable::new().with_delivery_mode(DeliveryMode::Nmi),
-    );
-
-    if ver.max_lvt_entry() > 2 {
-        lapic.write_reg(
-            LocalAPICReg::LVTError,
-            lvt::LocalVectorTable::new().with_vector(0xFE),
-        );
-    }
-
+    lapic.init(true);
     state.lapic = Some(lapic);
 }
@@ -1,6 +1,6 @@
 // Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
 
-use alloc::vec::Vec;
+use alloc::{boxed::Box, vec::Vec};
 
 use amd64::paging::PageTableFlags;
 
@@ -28,7 +28,15 @@ impl ACPIState {
             }
 
             debug!("Table: {ent:#X?}");
-            tables.push(ent);
+            // Take a copy, the firmware's is reclaimed after boot.
+            let data = unsafe {
+                core::slice::from_raw_parts(
+                    (ent as *const tables::SystemDescTableHeader).cast::<u8>(),
+                    ent.length(),
+                )
+            };
+            let copy: &'static [u8] = Box::leak(data.into());
+            tables.push(unsafe { &*copy.as_ptr().cast() });
         }
 
         Self {
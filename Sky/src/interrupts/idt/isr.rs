@@ -5,6 +5,11 @@ macro_rules! isr_stub {
         core::arch::naked_asm!(
             $err,
             "cld",
+            // Coming from user-land, `GS` has to point at the CPU data again.
+            "test byte ptr [rsp + 16], 3",
+            "jz 2f",
+            "swapgs",
+            "2:",
             "push {}",
             "push rax",
             "push rbx",
@@ -39,6 +44,10 @@ macro_rules! isr_stub {
             "pop rbx",
             "pop rax",
             "add rsp, 16",
+            "test byte ptr [rsp + 8], 3",
+            "jz 3f",
+            "swapgs",
+            "3:",
             "iretq",
             const $i,
             sym isr_handler,
@@ -11,7 +11,7 @@ use acpi::{tables::rsdp::RootSystemDescPtr, ACPIState};
 use hashbrown::HashMap;
 use incr_id::IncrementalIDGen;
 use skykit::{osdtentry::OSDTENTRY_NAME_KEY, SKExtensions};
-use system::{pmm::BitmapAllocator, state::OSDTEntry};
+use system::{pmm::BuddyAllocator, state::OSDTEntry};
 
 #[macro_use]
 extern crate alloc;
@@ -23,7 +23,6 @@ extern crate itertools;
 extern crate bitfield_struct;
 
 mod acpi;
-mod bitmap;
 mod incr_id;
 mod interrupts;
 mod logger;
@@ -65,8 +64,9 @@ pub fn init_core(boot_info: &skyliftkit::BootInfo) {
         crate::interrupts::init_quirks();
         crate::system::exceptions::init();
     }
+    state.fpu = Some(crate::system::fpu::init());
 
-    state.pmm = Some(BitmapAllocator::new(boot_info.memory_map).into());
+    state.pmm = Some(BuddyAllocator::new(boot_info.memory_map).into());
 
     // Switch ownership of symbol data to kernel
     state.kern_symbols = Some(
@@ -142,11 +142,27 @@ extern "C" fn kernel_main(boot_info: &'static skyliftkit::BootInfo) -> ! {
 
     let fkcache: SKExtensions = postcard::from_bytes(boot_info.fkcache).unwrap();
     state.fkcache = Some(fkcache.into());
+    state.hpet = Some(acpi::get_hpet(state));
+    system::smp::init_bsp(state);
     state.scheduler =
-        Some(system::tasking::scheduler::Scheduler::new(&acpi::get_hpet(state)).into());
+        Some(system::tasking::scheduler::Scheduler::new(state.hpet.as_ref().unwrap()).into());
+    system::smp::start_aps(state, boot_info.memory_map);
+    system::smp::publish(state);
+
+    // Nothing refers to boot_info past this point.
+    let reclaimed = unsafe {
+        state
+            .pmm
+            .as_ref()
+            .unwrap()
+            .lock()
+            .reclaim(boot_info.memory_map)
+    };
+    info!("Reclaimed {} KiB of boot memory", reclaimed * 4);
 
     system::fkext::spawn_initial_matches();
 
+    debug!("Kernel heap: {:#X?}", system::allocator::stats());
     debug!("I'm out of here!");
     system::tasking::scheduler::Scheduler::unmask();
 
@@ -1,39 +1,332 @@
 // Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
 
+use core::alloc::Layout;
+
+use amd64::paging::{PAGE_SIZE, PHYS_VIRT_OFFSET};
+
 #[global_allocator]
-static GLOBAL_ALLOCATOR: KernAllocator = KernAllocator;
+static GLOBAL_ALLOCATOR: KernAllocator = KernAllocator(spin::Mutex::new(Heap::new()));
 
-struct KernAllocator;
+const MIN_OBJ_SHIFT: u32 = 4;
+/// Objects up to 2^`MAX_OBJ_SHIFT` bytes come from the slab caches, bigger ones get whole pages.
+const MAX_OBJ_SHIFT: u32 = 11;
+const CACHE_COUNT: usize = (MAX_OBJ_SHIFT - MIN_OBJ_SHIFT + 1) as usize;
 
-unsafe impl core::alloc::GlobalAlloc for KernAllocator {
-    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
-        let count = ((layout.pad_to_align().size() + 0xFFF) / 0x1000) as u64;
+/// Pages per slab of each cache, so that the bigger classes do not waste most of a slab on its
+/// header.
+const SLAB_PAGES: [u64; CACHE_COUNT] = [1, 1, 1, 1, 1, 2, 4, 8];
+
+/// Header at the start of every slab. Slabs are aligned to their size, so the header of an
+/// object is found by masking its address.
+#[repr(C)]
+struct Slab {
+    next: *mut Slab,
+    prev: *mut Slab,
+    free: *mut FreeObject,
+    in_use: u64,
+}
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CacheStats {
+    pub obj_size: u64,
+    pub slabs: u64,
+    pub capacity: u64,
+    pub in_use: u64,
+    pub allocs: u64,
+    pub frees: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HeapStats {
+    pub caches: [CacheStats; CACHE_COUNT],
+    /// Pages handed out directly, for allocations too big for the caches.
+    pub large_pages: u64,
+    pub large_allocs: u64,
+    pub large_frees: u64,
+}
+
+struct SlabCache {
+    /// Slabs with at least one free object.
+    partial: *mut Slab,
+    slab_pages: u64,
+    stats: CacheStats,
+}
+
+impl SlabCache {
+    const fn new(shift: u32) -> Self {
+        Self {
+            partial: core::ptr::null_mut(),
+            slab_pages: SLAB_PAGES[(shift - MIN_OBJ_SHIFT) as usize],
+            stats: CacheStats {
+                obj_size: 1 << shift,
+                slabs: 0,
+                capacity: 0,
+                in_use: 0,
+                allocs: 0,
+                frees: 0,
+            },
+        }
+    }
+
+    const fn slab_size(&self) -> u64 {
+        self.slab_pages * PAGE_SIZE
+    }
+
+    /// Offset of the first object in a slab, past the header. Objects stay aligned to their size.
+    const fn first_obj(&self) -> u64 {
+        (core::mem::size_of::<Slab>() as u64).next_multiple_of(self.stats.obj_size)
+    }
+
+    const fn objs_per_slab(&self) -> u64 {
+        (self.slab_size() - self.first_obj()) / self.stats.obj_size
+    }
+
+    unsafe fn unlink(&mut self, slab: *mut Slab) {
+        let Slab { next, prev, .. } = *slab;
+        if prev.is_null() {
+            self.partial = next;
+        } else {
+            (*prev).next = next;
+        }
+        if !next.is_null() {
+            (*next).prev = prev;
+        }
+    }
+
+    unsafe fn push(&mut self, slab: *mut Slab) {
+        (*slab).prev = core::ptr::null_mut();
+        (*slab).next = self.partial;
+        if !self.partial.is_null() {
+            (*self.partial).prev = slab;
+        }
+        self.partial = slab;
+    }
+
+    /// Takes a slab from the PMM and threads all of its objects on the free list.
+    unsafe fn grow(&mut self) -> Option<*mut Slab> {
         let pmm = (*super::state::SYS_STATE.get()).pmm.as_ref().unwrap();
-        let ptr = pmm
+        let slab = pmm
             .lock()
-            .alloc(count)
-            .map_or(core::ptr::null_mut(), |ptr| {
-                ptr.add(amd64::paging::PHYS_VIRT_OFFSET as _)
-            });
-        if !ptr.is_null() {
-            ptr.write_bytes(0, (count * 0x1000) as _);
+            .alloc(self.slab_pages)?
+            .add(PHYS_VIRT_OFFSET as _) as *mut Slab;
+
+        let mut free = core::ptr::null_mut();
+        for off in (self.first_obj()..self.slab_size())
+            .step_by(self.stats.obj_size as _)
+            .rev()
+        {
+            let obj = slab.byte_add(off as _) as *mut FreeObject;
+            (*obj).next = free;
+            free = obj;
         }
-        ptr
+        slab.write(Slab {
+            next: core::ptr::null_mut(),
+            prev: core::ptr::null_mut(),
+            free,
+            in_use: 0,
+        });
+        self.push(slab);
+
+        self.stats.slabs += 1;
+        self.stats.capacity += self.objs_per_slab();
+        Some(slab)
     }
 
-    unsafe fn alloc_zeroed(&self, layout: core::alloc::Layout) -> *mut u8 {
-        self.alloc(layout) // Memory is already zeroed by the allocator by default
+    unsafe fn alloc(&mut self) -> *mut u8 {
+        let slab = if self.partial.is_null() {
+            let Some(slab) = self.grow() else {
+                return core::ptr::null_mut();
+            };
+            slab
+        } else {
+            self.partial
+        };
+
+        let obj = (*slab).free;
+        (*slab).free = (*obj).next;
+        (*slab).in_use += 1;
+        if (*slab).free.is_null() {
+            self.unlink(slab);
+        }
+
+        self.stats.in_use += 1;
+        self.stats.allocs += 1;
+        obj.cast()
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+    unsafe fn dealloc(&mut self, ptr: *mut u8) {
+        let slab = (ptr as u64 & !(self.slab_size() - 1)) as *mut Slab;
+        let was_full = (*slab).free.is_null();
+
+        let obj = ptr as *mut FreeObject;
+        (*obj).next = (*slab).free;
+        (*slab).free = obj;
+        (*slab).in_use -= 1;
+        self.stats.in_use -= 1;
+        self.stats.frees += 1;
+
+        if was_full {
+            self.push(slab);
+        }
+
+        // Give empty slabs back, but keep the last one around so a cache that hovers around a
+        // single object does not go to the PMM on every allocation.
+        if (*slab).in_use == 0 && !((*slab).prev.is_null() && (*slab).next.is_null()) {
+            self.unlink(slab);
+            self.stats.slabs -= 1;
+            self.stats.capacity -= self.objs_per_slab();
+            let pmm = (*super::state::SYS_STATE.get()).pmm.as_ref().unwrap();
+            pmm.lock()
+                .free(slab.byte_sub(PHYS_VIRT_OFFSET as _).cast(), self.slab_pages);
+        }
+    }
+}
+
+struct Heap {
+    caches: [SlabCache; CACHE_COUNT],
+    large_pages: u64,
+    large_allocs: u64,
+    large_frees: u64,
+}
+
+unsafe impl Send for Heap {}
+
+impl Heap {
+    const fn new() -> Self {
+        Self {
+            caches: [
+                SlabCache::new(4),
+                SlabCache::new(5),
+                SlabCache::new(6),
+                SlabCache::new(7),
+                SlabCache::new(8),
+                SlabCache::new(9),
+                SlabCache::new(10),
+                SlabCache::new(11),
+            ],
+            large_pages: 0,
+            large_allocs: 0,
+            large_frees: 0,
+        }
+    }
+
+    /// Index of the cache serving `layout`, if any. Size classes are powers of two and objects
+    /// are aligned to their size, so the alignment only ever bumps the class.
+    fn cache_for(layout: Layout) -> Option<usize> {
+        let size = layout.size().max(layout.align()).next_power_of_two();
+        let shift = size.trailing_zeros().max(MIN_OBJ_SHIFT);
+        (shift <= MAX_OBJ_SHIFT).then_some((shift - MIN_OBJ_SHIFT) as usize)
+    }
+
+    const fn page_count(layout: Layout) -> u64 {
+        (layout.size() as u64).div_ceil(PAGE_SIZE)
+    }
+
+    /// Allocates zeroed whole pages.
+    unsafe fn alloc_pages(&mut self, layout: Layout) -> *mut u8 {
+        let count = Self::page_count(layout);
+        let pmm = (*super::state::SYS_STATE.get()).pmm.as_ref().unwrap();
+        let ptr = if layout.align() as u64 > PAGE_SIZE {
+            pmm.lock()
+                .alloc_aligned(count, layout.align() as u64 / PAGE_SIZE, u64::MAX)
+        } else {
+            pmm.lock().alloc(count)
+        };
+        let Some(ptr) = ptr else {
+            return core::ptr::null_mut();
+        };
+
+        let ptr = ptr.add(PHYS_VIRT_OFFSET as _);
+        ptr.write_bytes(0, (count * PAGE_SIZE) as _);
+        self.large_pages += count;
+        self.large_allocs += 1;
+        ptr
+    }
+
+    unsafe fn dealloc_pages(&mut self, ptr: *mut u8, layout: Layout) {
+        let count = Self::page_count(layout);
         let pmm = (*super::state::SYS_STATE.get()).pmm.as_ref().unwrap();
-        pmm.lock().free(
-            ptr.sub(amd64::paging::PHYS_VIRT_OFFSET as _),
-            ((layout.pad_to_align().size() + 0xFFF) / 0x1000) as _,
+        pmm.lock().free(ptr.sub(PHYS_VIRT_OFFSET as _), count);
+        self.large_pages -= count;
+        self.large_frees += 1;
+    }
+}
+
+struct KernAllocator(spin::Mutex<Heap>);
+
+unsafe impl core::alloc::GlobalAlloc for KernAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut heap = self.0.lock();
+        match Heap::cache_for(layout) {
+            Some(i) => heap.caches[i].alloc(),
+            None => heap.alloc_pages(layout),
+        }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let mut heap = self.0.lock();
+        match Heap::cache_for(layout) {
+            Some(i) => {
+                let ptr = heap.caches[i].alloc();
+                if !ptr.is_null() {
+                    ptr.write_bytes(0, layout.size());
+                }
+                ptr
+            }
+            None => heap.alloc_pages(layout), // Pages are always zeroed
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut heap = self.0.lock();
+        match Heap::cache_for(layout) {
+            Some(i) => heap.caches[i].dealloc(ptr),
+            None => heap.dealloc_pages(ptr, layout),
+        }
+    }
+}
+
+pub fn stats() -> HeapStats {
+    let heap = GLOBAL_ALLOCATOR.0.lock();
+    HeapStats {
+        caches: core::array::from_fn(|i| heap.caches[i].stats),
+        large_pages: heap.large_pages,
+        large_allocs: heap.large_allocs,
+        large_frees: heap.large_frees,
+    }
+}
+
+/// Allocates a zeroed buffer of `len` bytes on whole pages of its own, straight from the PMM.
+/// Buffers handed over to user space must come from here, as processes map and free them by the
+/// page. They are not part of the heap and do not show up in its statistics.
+pub fn alloc_pages(len: usize) -> &'static mut [u8] {
+    let count = (len as u64).div_ceil(PAGE_SIZE).max(1);
+    let pmm = unsafe { (*super::state::SYS_STATE.get()).pmm.as_ref().unwrap() };
+    let Some(ptr) = (unsafe { pmm.lock().alloc(count) }) else {
+        alloc::alloc::handle_alloc_error(
+            Layout::from_size_align((count * PAGE_SIZE) as _, PAGE_SIZE as _).unwrap(),
         );
+    };
+    unsafe {
+        let ptr = ptr.add(PHYS_VIRT_OFFSET as _);
+        ptr.write_bytes(0, (count * PAGE_SIZE) as _);
+        core::slice::from_raw_parts_mut(ptr, len)
     }
 }
 
+/// Gives back a buffer from [`alloc_pages`] that was never handed over.
+pub unsafe fn free_pages(buf: &mut [u8]) {
+    let pmm = (*super::state::SYS_STATE.get()).pmm.as_ref().unwrap();
+    pmm.lock().free(
+        buf.as_mut_ptr().sub(PHYS_VIRT_OFFSET as _),
+        (buf.len() as u64).div_ceil(PAGE_SIZE).max(1),
+    );
+}
+
 #[alloc_error_handler]
 pub fn alloc_error(layout: core::alloc::Layout) -> ! {
     panic!("Failed to allocate memory: {layout:#X?}");
@@ -13,7 +13,11 @@ macro_rules! exception_msg {
             panic!("Received {} exception: {}", $name, $msg);
         } else {
             use core::fmt::Write;
-            let mut scheduler = sys_state.scheduler.as_ref().unwrap().lock();
+            let mut scheduler = crate::system::smp::lock_scheduler();
+            if scheduler.current_thread().unwrap().state.is_dying() {
+                scheduler.schedule($regs);
+                return;
+            }
             let cur_proc = scheduler.current_process().unwrap();
             let image_base = cur_proc.image_base;
             let proc_path = &cur_proc.path;
@@ -45,7 +49,10 @@ macro_rules! exception_msg {
                 }
             }
 
-            scheduler.process_teardown();
+            scheduler.process_teardown(
+                u64::MAX,
+                Some(skykit::TerminationReason::UnhandledException),
+            );
             scheduler.schedule($regs);
         }
     };
@@ -4,6 +4,25 @@ pub unsafe extern "sysv64" fn page_fault(regs: &mut crate::system::RegisterState
     let mut cr2: u64;
     core::arch::asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags));
 
+    // Non-present user access, possibly to a lazily backed allocation
+    if (regs.err_code & 0b101) == 0b100 {
+        let mut scheduler = crate::system::smp::lock_scheduler();
+        if !scheduler.current_thread().unwrap().state.is_dying() {
+            let res = scheduler.current_process_mut().unwrap().back_page(cr2);
+            drop(scheduler);
+            let msg = match res {
+                Ok(()) => return,
+                Err(crate::system::tasking::FaultError::Unallocated) => {
+                    format!("{cr2:#X?} is not part of any allocation.")
+                }
+                Err(crate::system::tasking::FaultError::OutOfMemory) => {
+                    format!("Ran out of memory backing {cr2:#X?}.")
+                }
+            };
+            super::exception_msg!("page fault", msg, regs);
+        }
+    }
+
     let msg = format!(
         "There was a {} while {} a {} page at {cr2:#X?}.{}{}{}{}",
         if (regs.err_code & (1 << 0)) == 0 {
@@ -5,11 +5,13 @@ use core::hash::Hash;
 
 use hashbrown::HashMap;
 use skykit::{
-    osdtentry::{OSDTENTRY_NAME_KEY, SKEXT_MATCH_KEY, SKEXT_PROC_KEY},
-    SKExtension,
+    osdtentry::{OSDTENTRY_NAME_KEY, SKEXT_EXIT_KEY, SKEXT_MATCH_KEY, SKEXT_PROC_KEY},
+    osvalue::OSValue,
+    process::SpawnRequest,
+    SKExtension, SkyError,
 };
 
-use super::tasking::scheduler::Scheduler;
+use super::tasking::{scheduler::Scheduler, userland::handlers::os_dt_entry::is_within};
 use crate::incr_id::IncrementalIDGen;
 
 fn is_subset<K: Eq + Hash, V: Eq>(a: &HashMap<K, V>, b: &HashMap<K, V>) -> bool {
@@ -20,38 +22,172 @@ fn is_subset<K: Eq + Hash, V: Eq>(a: &HashMap<K, V>, b: &HashMap<K, V>) -> bool
     a.iter().all(|(k, v)| b.get(k) == Some(v))
 }
 
+/// Finds the extension owning the subtree `ent` is in.
+fn owner_pid(
+    dt_index: &HashMap<u64, spin::Mutex<super::state::OSDTEntry>>,
+    ent: &super::state::OSDTEntry,
+) -> Option<u64> {
+    let mut prop = ent.properties.get(SKEXT_PROC_KEY).cloned();
+    let mut parent = ent.parent;
+    while prop.is_none() {
+        let v = dt_index.get::<u64>(&parent?.into())?.lock();
+        prop = v.properties.get(SKEXT_PROC_KEY).cloned();
+        parent = v.parent;
+    }
+    prop?.try_into().ok()
+}
+
+/// `personality` is `None` when spawned on request rather than by matching.
+/// `args` is copied into the new process and passed to its entry point along with the OSDT entry.
+/// Returns [`None`] if out of memory, leaving nothing behind.
+#[allow(clippy::too_many_arguments)]
 fn load_fkext(
     ent: &mut super::state::OSDTEntry,
     info: &SKExtension,
-    personality: &str,
+    personality: Option<&str>,
     payload: &[u8],
+    args: &[u8],
+    parent: Option<u64>,
     dt_id_gen: &mut IncrementalIDGen,
     scheduler: &mut Scheduler,
-) -> (u64, spin::Mutex<super::state::OSDTEntry>) {
-    debug!(
-        "SkyKit extension {} matched <{}> personality {personality}",
-        info.identifier, ent.id
-    );
-    let thread = scheduler.spawn_proc(info.identifier.clone(), payload);
+) -> Option<(u64, super::state::OSDTEntry)> {
+    match personality {
+        Some(personality) => debug!(
+            "SkyKit extension {} matched <{}> personality {personality}",
+            info.identifier, ent.id
+        ),
+        None => debug!(
+            "SkyKit extension {} spawned under <{}> by PID {parent:?}",
+            info.identifier, ent.id
+        ),
+    }
+    let id = dt_id_gen.next();
+    let mut caps = info.capabilities.clone();
+    caps.osdt_entries.push(id);
+    let Some(thread) = scheduler.spawn_proc(info.identifier.clone(), payload, parent, caps) else {
+        warn!(
+            "Out of memory spawning SkyKit extension {}",
+            info.identifier
+        );
+        dt_id_gen.free(id);
+        return None;
+    };
+    thread.regs.rdi = id;
+    let (pid, tid) = (thread.pid, thread.id);
+
+    if !args.is_empty() {
+        let proc = scheduler.processes.get_mut(&pid).unwrap();
+        let Some((addr, _)) =
+            proc.allocate(crate::system::tasking::vma::Area::Heap, args.len() as _)
+        else {
+            warn!(
+                "Out of memory passing arguments to SkyKit extension {}",
+                info.identifier
+            );
+            scheduler.discard_proc(pid);
+            dt_id_gen.free(id);
+            return None;
+        };
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                args.as_ptr(),
+                proc.kern_addr(addr) as *mut u8,
+                args.len(),
+            );
+        }
+        let thread = scheduler.threads.get_mut(&tid).unwrap();
+        thread.regs.rsi = addr;
+        thread.regs.rdx = args.len() as _;
+    }
+
+    let mut properties = HashMap::from([
+        (
+            OSDTENTRY_NAME_KEY.into(),
+            info.identifier.rsplit('.').next().unwrap().into(),
+        ),
+        (SKEXT_PROC_KEY.into(), pid.into()),
+    ]);
+    if let Some(personality) = personality {
+        properties.insert(
+            SKEXT_MATCH_KEY.into(),
+            (info.identifier.as_str(), personality).into(),
+        );
+    }
     let new = super::state::OSDTEntry {
-        id: dt_id_gen.next(),
+        id,
         parent: Some(ent.id.into()),
-        properties: HashMap::from([
-            (
-                OSDTENTRY_NAME_KEY.into(),
-                info.identifier.rsplit('.').next().unwrap().into(),
-            ),
-            (
-                SKEXT_MATCH_KEY.into(),
-                (info.identifier.as_str(), personality).into(),
-            ),
-            (SKEXT_PROC_KEY.into(), thread.pid.into()),
-        ]),
+        properties,
         ..Default::default()
     };
     ent.children.push(new.id.into());
-    thread.regs.rdi = new.id;
-    (new.id, new.into())
+    Some((pid, new))
+}
+
+/// Starts the cached extension `req.identifier` on behalf of `caller`.
+/// Returns the new PID and OSDT entry.
+pub fn spawn(
+    scheduler: &mut Scheduler,
+    caller: u64,
+    req: &SpawnRequest,
+) -> Result<(u64, u64), SkyError> {
+    let state = unsafe { &*super::state::SYS_STATE.get() };
+
+    let fkcache = state.fkcache.as_ref().unwrap().lock();
+    let Some((info, payload)) = fkcache
+        .0
+        .iter()
+        .find(|(v, _)| v.identifier == req.identifier)
+    else {
+        return Err(SkyError::NotFound);
+    };
+
+    let dt_index = state.dt_index.as_ref().unwrap();
+    let (pid, new) = {
+        let index = dt_index.read();
+        let parent: u64 = req.parent.into();
+        let Some(ent) = index.get(&parent) else {
+            return Err(SkyError::NotFound);
+        };
+        let roots = &scheduler.processes.get(&caller).unwrap().caps.osdt_entries;
+        if !is_within(&index, roots, parent) {
+            return Err(SkyError::InsufficientPermissions);
+        }
+        let mut dt_id_gen = state.dt_id_gen.as_ref().unwrap().lock();
+        load_fkext(
+            &mut ent.lock(),
+            info,
+            None,
+            payload,
+            &req.args,
+            Some(caller),
+            &mut dt_id_gen,
+            scheduler,
+        )
+        .ok_or(SkyError::OutOfMemory)?
+    };
+    let id = new.id;
+    dt_index.write().insert(id, new.into());
+
+    Ok((pid, id))
+}
+
+/// Records the exit code on the OSDT entry of the extension running as `pid`,
+/// so the stale PID is not mistaken for whichever process reuses it.
+pub fn mark_exited(pid: u64, code: u64) {
+    let state = unsafe { &*super::state::SYS_STATE.get() };
+    let Some(dt_index) = state.dt_index.as_ref() else {
+        return;
+    };
+
+    let pid = OSValue::from(pid);
+    for ent in dt_index.read().values() {
+        let mut ent = ent.lock();
+        if ent.properties.get(SKEXT_PROC_KEY) == Some(&pid) {
+            ent.properties.remove(SKEXT_PROC_KEY);
+            ent.properties.insert(SKEXT_EXIT_KEY.into(), code.into());
+            break;
+        }
+    }
 }
 
 pub fn handle_change(scheduler: &mut Scheduler, ent: skykit::osdtentry::OSDTEntry) {
@@ -75,14 +211,18 @@ pub fn handle_change(scheduler: &mut Scheduler, ent: skykit::osdtentry::OSDTEntr
                         .filter_map(|id| dt_index.get::<u64>(&id.into()))
                         .any(|v| v.lock().properties.get(SKEXT_MATCH_KEY) == Some(&match_));
                     if !attached && is_subset(matching, &ent.properties) {
-                        return Some(load_fkext(
+                        let parent = owner_pid(&dt_index, &ent);
+                        let (_, new) = load_fkext(
                             &mut ent,
                             info,
-                            personality,
+                            Some(personality),
                             payload,
+                            &[],
+                            parent,
                             &mut dt_id_gen,
                             scheduler,
-                        ));
+                        )?;
+                        return Some((new.id, new.into()));
                     }
                 }
                 None
@@ -98,28 +238,32 @@ pub fn spawn_initial_matches() {
 
     let dt_index = state.dt_index.as_ref().unwrap();
     let mut dt_id_gen = state.dt_id_gen.as_ref().unwrap().lock();
-    let mut scheduler = state.scheduler.as_ref().unwrap().lock();
+    let mut scheduler = crate::system::smp::lock_scheduler();
 
     let mut newly_matched = vec![];
-    for ((info, payload), mut ent) in iproduct!(
-        &state.fkcache.as_ref().unwrap().lock().0,
-        dt_index.read().values()
-    )
-    .map(|(info, ent)| (info, ent.lock()))
+    let index = dt_index.read();
+    for ((info, payload), mut ent) in
+        iproduct!(&state.fkcache.as_ref().unwrap().lock().0, index.values())
+            .map(|(info, ent)| (info, ent.lock()))
     {
         for (personality, matching) in &info.personalities {
             if is_subset(matching, &ent.properties) {
-                let new = load_fkext(
+                let parent = owner_pid(&index, &ent);
+                if let Some((_, new)) = load_fkext(
                     &mut ent,
                     info,
-                    personality,
+                    Some(personality),
                     payload,
+                    &[],
+                    parent,
                     &mut dt_id_gen,
                     &mut scheduler,
-                );
-                newly_matched.push(new);
+                ) {
+                    newly_matched.push((new.id, new.into()));
+                }
             }
         }
     }
+    drop(index);
     dt_index.write().extend(newly_matched);
 }
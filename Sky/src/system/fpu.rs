@@ -0,0 +1,145 @@
+// Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use alloc::alloc::Layout;
+use core::ptr::NonNull;
+
+use amd64::{
+    cpuid::{CPUIdentification, ExtendedStateInfo},
+    cr::{ControlReg0, ControlReg4, ExtendedControlReg0},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SaveMethod {
+    XSave,
+    FxSave,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FpuInfo {
+    pub method: SaveMethod,
+    /// Size of the area the method saves to.
+    pub size: usize,
+}
+
+/// Enables x87, SSE and, where supported, AVX for user-land.
+pub fn init() -> FpuInfo {
+    let ident = CPUIdentification::new();
+    assert!(ident.features.fxsr() && ident.features.sse2());
+    let xsave = ExtendedStateInfo::new(&ident);
+
+    unsafe {
+        ControlReg0::read()
+            .with_emulation(false)
+            .with_monitor_coprocessor(true)
+            .with_task_switched(false)
+            .with_numeric_error(true)
+            .write();
+        ControlReg4::read()
+            .with_os_fxsr(true)
+            .with_os_xmm_exceptions(true)
+            .with_os_xsave(xsave.is_some())
+            .write();
+        core::arch::asm!("fninit", options(nomem, nostack));
+    }
+
+    let Some(xsave) = xsave else {
+        debug!("XSAVE not supported, falling back to FXSAVE");
+        return FpuInfo {
+            method: SaveMethod::FxSave,
+            size: 512,
+        };
+    };
+
+    let xcr0 = ExtendedControlReg0::new()
+        .with_x87(true)
+        .with_sse(true)
+        .with_avx(xsave.supported.avx());
+    unsafe { xcr0.write() }
+    // The reported size depends on what was just enabled.
+    let size = ExtendedStateInfo::new(&ident).unwrap().enabled_size as usize;
+    debug!("XSAVE enabled with {xcr0:?}, {size} byte area");
+
+    FpuInfo {
+        method: SaveMethod::XSave,
+        size,
+    }
+}
+
+/// Saved x87/SSE/AVX registers of a thread.
+#[derive(Debug)]
+pub struct ExtendedState {
+    ptr: NonNull<u8>,
+    info: FpuInfo,
+}
+
+unsafe impl Send for ExtendedState {}
+
+impl ExtendedState {
+    /// Both methods need the area 64-byte aligned at most.
+    const ALIGN: usize = 64;
+    /// Offset of the x87 control word and MXCSR in the legacy area.
+    const FCW_OFFSET: usize = 0;
+    const MXCSR_OFFSET: usize = 24;
+
+    /// Starts out with every exception masked, same as after `FNINIT`.
+    pub fn new() -> Self {
+        let info = unsafe { (*super::state::SYS_STATE.get()).fpu.unwrap() };
+        let ptr = unsafe { alloc::alloc::alloc_zeroed(Self::layout(&info)) };
+        let ptr = NonNull::new(ptr).expect("Failed to allocate extended state");
+        unsafe {
+            ptr.add(Self::FCW_OFFSET).cast::<u16>().write(0x37F);
+            ptr.add(Self::MXCSR_OFFSET).cast::<u32>().write(0x1F80);
+        }
+        Self { ptr, info }
+    }
+
+    fn layout(info: &FpuInfo) -> Layout {
+        Layout::from_size_align(info.size, Self::ALIGN).unwrap()
+    }
+
+    pub unsafe fn save(&mut self) {
+        match self.info.method {
+            SaveMethod::XSave => core::arch::asm!(
+                "xsave64 [{}]",
+                in(reg) self.ptr.as_ptr(),
+                in("eax") u32::MAX,
+                in("edx") u32::MAX,
+                options(nostack, preserves_flags),
+            ),
+            SaveMethod::FxSave => core::arch::asm!(
+                "fxsave64 [{}]",
+                in(reg) self.ptr.as_ptr(),
+                options(nostack, preserves_flags),
+            ),
+        }
+    }
+
+    pub unsafe fn restore(&self) {
+        match self.info.method {
+            SaveMethod::XSave => core::arch::asm!(
+                "xrstor64 [{}]",
+                in(reg) self.ptr.as_ptr(),
+                in("eax") u32::MAX,
+                in("edx") u32::MAX,
+                options(nostack, preserves_flags),
+            ),
+            SaveMethod::FxSave => core::arch::asm!(
+                "fxrstor64 [{}]",
+                in(reg) self.ptr.as_ptr(),
+                options(nostack, preserves_flags),
+            ),
+        }
+    }
+}
+
+impl Default for ExtendedState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for ExtendedState {
+    fn drop(&mut self) {
+        unsafe { alloc::alloc::dealloc(self.ptr.as_ptr(), Self::layout(&self.info)) }
+    }
+}
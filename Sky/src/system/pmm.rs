@@ -1,68 +1,65 @@
 // Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
 
-use amd64::paging::PAGE_SIZE;
+use amd64::paging::{buddy, PAGE_SIZE};
 use skyliftkit::{MemoryData, MemoryEntry};
 
-pub struct BitmapAllocator {
-    bitmap: &'static mut [u64],
-    highest_addr: u64,
-    pub free_pages: u64,
-    pub total_pages: u64,
-    last_index: u64,
-}
+/// Buddy allocator over the physical memory described by the memory map, with its metadata
+/// placed in the first usable region large enough for it.
+pub struct BuddyAllocator(buddy::BuddyAllocator<'static>);
 
-impl BitmapAllocator {
+impl BuddyAllocator {
     #[inline]
     pub fn new(mmap: &'static [MemoryEntry]) -> Self {
+        // Leave room for the memory reclaimed after boot.
         let highest_addr = mmap
             .iter()
-            .flat_map(|v| {
-                let MemoryEntry::Usable(v) = v else {
-                    return None;
-                };
-                Some(v.base + v.length)
+            .flat_map(|v| match v {
+                MemoryEntry::Usable(v)
+                | MemoryEntry::BootLoaderReclaimable(v)
+                | MemoryEntry::ACPIReclaimable(v) => Some(v.base + v.length),
+                _ => None,
             })
             .max()
             .unwrap();
 
-        let bitmap_sz = (highest_addr / PAGE_SIZE) / 8 + 8; // Why + 8?
+        let total_pages = highest_addr / PAGE_SIZE;
+        let (bitmap_sz, links_sz, orders_sz) = buddy::BuddyAllocator::metadata_size(total_pages);
+        let meta_sz = (bitmap_sz + links_sz + orders_sz).next_multiple_of(PAGE_SIZE);
         debug!(
-            "Highest usable address: {highest_addr:#X?}, Bitmap size: {bitmap_sz} bytes, {} \
-             entries",
-            bitmap_sz / 8
+            "Highest usable address: {highest_addr:#X?}, {total_pages} pages, metadata size: \
+             {meta_sz} bytes"
         );
 
-        let mut bitmap: &mut [u64] = Default::default();
-
-        let mut free_pages = 0;
-
-        for v in mmap.iter().flat_map(|v| {
-            let MemoryEntry::Usable(v) = v else {
-                return None;
-            };
-            // Skip the first 2 MiB.
-            Some(if v.base <= 0x20_0000 && v.base + v.length > 0x20_0000 {
-                MemoryData::new(0x20_0000, v.length - 0x20_0000)
-            } else {
-                *v
+        let regions = || {
+            mmap.iter().filter_map(|v| {
+                let MemoryEntry::Usable(v) = v else {
+                    return None;
+                };
+                Self::above_low_mem(v)
             })
-        }) {
-            if v.length == 0 {
-                continue;
-            }
-
+        };
+
+        let meta_base = regions()
+            .find(|v| v.length >= meta_sz)
+            .expect("No room for the PMM metadata")
+            .base;
+        trace!("Metadata is at {meta_base:#X?}");
+        let mut this = unsafe {
+            let virt = meta_base + amd64::paging::PHYS_VIRT_OFFSET;
+            Self(buddy::BuddyAllocator::new(
+                core::slice::from_raw_parts_mut(virt as *mut _, (bitmap_sz / 8) as _),
+                core::slice::from_raw_parts_mut(
+                    (virt + bitmap_sz + links_sz) as *mut _,
+                    orders_sz as _,
+                ),
+                core::slice::from_raw_parts_mut((virt + bitmap_sz) as *mut _, total_pages as _),
+            ))
+        };
+
+        for v in regions() {
             debug!("Base: {:#X?}, End: {:#X?}", v.base, v.base + v.length);
-            let v = if bitmap.is_empty() && v.length >= bitmap_sz {
-                bitmap = unsafe {
-                    core::slice::from_raw_parts_mut(
-                        (v.base + amd64::paging::PHYS_VIRT_OFFSET) as *mut _,
-                        (bitmap_sz / 8) as _,
-                    )
-                };
-                bitmap.fill(!0u64);
-
-                trace!("Bitmap is here");
-                MemoryData::new(v.base + bitmap_sz, v.length - bitmap_sz)
+            let v = if v.base == meta_base {
+                MemoryData::new(v.base + meta_sz, v.length - meta_sz)
             } else {
                 v
             };
@@ -73,80 +70,52 @@ impl BitmapAllocator {
                 "Base: {base:#X?}, Count: {count:#X?}, End: {}",
                 base + count
             );
-            for i in base..(base + count) {
-                crate::bitmap::bit_reset(bitmap, i);
-            }
-            free_pages += count;
+            unsafe { this.free((base * PAGE_SIZE) as *mut _, count) }
         }
 
-        let total_pages = highest_addr / PAGE_SIZE;
-        Self {
-            bitmap,
-            highest_addr,
-            total_pages,
-            free_pages,
-            last_index: 0,
-        }
+        debug!("Free blocks per order: {:?}", this.free_blocks);
+        this
     }
 
-    unsafe fn internal_alloc(&mut self, count: u64, limit: u64) -> Option<*mut u8> {
-        let mut n = 0;
-
-        while self.last_index < limit {
-            let set = crate::bitmap::bit_test(self.bitmap, self.last_index);
-            self.last_index += 1;
-            if set {
-                n = 0;
-                continue;
-            }
-
-            n += 1;
-
-            if n == count {
-                let page = self.last_index - count;
-
-                for i in page..self.last_index {
-                    crate::bitmap::bit_set(self.bitmap, i);
-                }
-
-                self.free_pages -= count;
+    /// The part of the region past the first 2 MiB, which are never handed out.
+    fn above_low_mem(v: &MemoryData) -> Option<MemoryData> {
+        let end = v.base + v.length;
+        (end > 0x20_0000).then(|| {
+            let base = v.base.max(0x20_0000);
+            MemoryData::new(base, end - base)
+        })
+    }
 
-                return Some((page * PAGE_SIZE) as *mut _);
+    /// Frees the bootloader and ACPI reclaimable regions of `mmap`, which nothing may be using
+    /// anymore. `mmap` itself may live in one of them, which is fine as long as the lock is held,
+    /// as the PMM keeps no state in free memory. Returns the number of pages reclaimed.
+    pub unsafe fn reclaim(&mut self, mmap: &[MemoryEntry]) -> u64 {
+        let mut count = 0;
+        for v in mmap.iter().filter_map(|v| match v {
+            MemoryEntry::BootLoaderReclaimable(v) | MemoryEntry::ACPIReclaimable(v) => {
+                Self::above_low_mem(v)
             }
+            _ => None,
+        }) {
+            debug!("Reclaiming {:#X?} to {:#X?}", v.base, v.base + v.length);
+            let pages = v.length / PAGE_SIZE;
+            self.free(v.base as *mut _, pages);
+            count += pages;
         }
-
-        None
-    }
-
-    pub unsafe fn alloc(&mut self, count: u64) -> Option<*mut u8> {
-        let l = self.last_index;
-
-        self.internal_alloc(count, self.highest_addr / PAGE_SIZE)
-            .or_else(|| {
-                self.last_index = 0;
-                self.internal_alloc(count, l)
-            })
+        count
     }
+}
 
-    pub unsafe fn free(&mut self, ptr: *mut u8, count: u64) {
-        let idx = ptr as u64 / PAGE_SIZE;
-
-        for i in idx..(idx + count) {
-            crate::bitmap::bit_reset(self.bitmap, i);
-        }
+impl core::ops::Deref for BuddyAllocator {
+    type Target = buddy::BuddyAllocator<'static>;
 
-        self.free_pages += count;
+    fn deref(&self) -> &Self::Target {
+        &self.0
     }
+}
 
-    pub fn is_allocated(&self, ptr: *mut u8, count: u64) -> bool {
-        let idx = ptr as u64 / PAGE_SIZE;
-
-        for i in idx..(idx + count) {
-            if !crate::bitmap::bit_test(self.bitmap, i) {
-                return false;
-            }
-        }
-
-        true
+impl core::ops::DerefMut for BuddyAllocator {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
     }
 }
@@ -0,0 +1,417 @@
+// Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    cell::SyncUnsafeCell,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use amd64::{
+    cr::{ControlReg0, ControlReg4},
+    msr::{
+        efer::ExtendedFeatureEnableReg, gs_base::GsBase, kernel_gs_base::KernelGsBase,
+        ModelSpecificReg,
+    },
+};
+use hashbrown::HashMap;
+use skykit::osdtentry::OSDTENTRY_NAME_KEY;
+use skyliftkit::MemoryEntry;
+
+use crate::{
+    acpi::apic::{DeliveryMode, InterruptCommand},
+    system::{
+        gdt::{GDTData, GDTReg, PrivilegeLevel, SegmentSelector},
+        state::{OSDTEntry, SystemState, SYS_STATE},
+        tasking::scheduler::Scheduler,
+        tss::TaskSegmentSelector,
+        RegisterState,
+    },
+    timer::Timer,
+};
+
+mod trampoline;
+
+pub const TIMER_VECTOR: u8 = 128;
+pub const RESCHEDULE_VECTOR: u8 = 129;
+pub const TLB_FLUSH_VECTOR: u8 = 130;
+
+const KERN_STACK_SIZE: usize = 0x14000;
+const AP_BOOT_STACK_SIZE: usize = 0x4000;
+
+/// State private to one CPU, reachable through `GS` while in the kernel.
+#[repr(C)]
+pub struct Cpu {
+    /// Address of this structure, read back through `gs:0`.
+    this: u64,
+    /// User RSP, stashed by the `SYSCALL` entry while switching to the kernel stack.
+    pub user_rsp: u64,
+    /// Top of [`Self::kern_stack`].
+    pub kern_rsp: u64,
+    /// Index of the CPU, the BSP being 0.
+    pub id: usize,
+    pub apic_id: u8,
+    pub online: AtomicBool,
+    /// Last TLB shootdown generation this CPU has flushed for.
+    pub tlb_gen: AtomicU64,
+    pub current_tid: Option<u64>,
+    pub current_pid: Option<u64>,
+    /// Process whose I/O permission bitmap is loaded in the TSS.
+    pub io_bitmap_owner: Option<u64>,
+    pub kern_stack: Vec<u8>,
+    pub gdt: GDTData,
+    pub tss: TaskSegmentSelector,
+}
+
+/// Generation of the latest TLB shootdown.
+static TLB_GEN: AtomicU64 = AtomicU64::new(0);
+
+/// Control registers the APs copy from the BSP.
+static BSP_REGS: SyncUnsafeCell<Option<(ControlReg0, ControlReg4, ExtendedFeatureEnableReg)>> =
+    SyncUnsafeCell::new(None);
+
+impl Cpu {
+    pub fn new(id: usize, apic_id: u8) -> &'static mut Self {
+        let kern_stack = vec![0; KERN_STACK_SIZE];
+        let kern_rsp = kern_stack.as_ptr() as u64 + kern_stack.len() as u64;
+        let this = Box::leak(Box::new(Self {
+            this: 0,
+            user_rsp: 0,
+            kern_rsp,
+            id,
+            apic_id,
+            online: AtomicBool::new(false),
+            tlb_gen: AtomicU64::new(0),
+            current_tid: None,
+            current_pid: None,
+            io_bitmap_owner: None,
+            kern_stack,
+            gdt: GDTData::new(),
+            tss: TaskSegmentSelector::new(kern_rsp),
+        }));
+        this.this = this as *mut Self as u64;
+        this
+    }
+
+    /// Loads the GDT and TSS of this CPU on the calling CPU, and points `GS` at it.
+    pub unsafe fn load(&mut self) {
+        let tss_addr = core::ptr::addr_of!(self.tss) as u64;
+        let ts = &mut self.gdt.task_segment;
+        ts.length = (core::mem::size_of::<TaskSegmentSelector>() - 1) as u16;
+        ts.base_low = tss_addr as u16;
+        ts.base_middle = (tss_addr >> 16) as u8;
+        ts.attrs = ts.attrs.with_present(true);
+        ts.base_high = (tss_addr >> 24) as u8;
+        ts.base_upper = (tss_addr >> 32) as u32;
+
+        GDTReg {
+            limit: (core::mem::size_of::<GDTData>() - 1) as u16,
+            addr: &self.gdt,
+        }
+        .load();
+        core::arch::asm!(
+            "ltr ax",
+            in("ax") SegmentSelector::new(5, PrivilegeLevel::Supervisor).0,
+            options(nostack, preserves_flags),
+        );
+
+        GsBase::new().with_base(self.this).write();
+        KernelGsBase::new().write();
+    }
+}
+
+/// The CPU this runs on. Only valid in the kernel once [`init_bsp`] ran.
+pub fn current() -> &'static mut Cpu {
+    unsafe {
+        let this: u64;
+        core::arch::asm!(
+            "mov {}, gs:[0]",
+            out(reg) this,
+            options(nostack, readonly, preserves_flags),
+        );
+        &mut *(this as *mut Cpu)
+    }
+}
+
+pub fn init_bsp(state: &mut SystemState) {
+    let cpu = Cpu::new(0, state.lapic.as_ref().unwrap().id());
+    unsafe { cpu.load() }
+    cpu.online.store(true, Ordering::Release);
+    state.cpus.push(cpu);
+
+    crate::interrupts::idt::set_handler(
+        RESCHEDULE_VECTOR,
+        1,
+        PrivilegeLevel::Supervisor,
+        reschedule_handler,
+        true,
+        true,
+    );
+    crate::interrupts::idt::set_handler(
+        TLB_FLUSH_VECTOR,
+        0,
+        PrivilegeLevel::Supervisor,
+        tlb_flush_handler,
+        true,
+        true,
+    );
+}
+
+unsafe extern "sysv64" fn reschedule_handler(state: &mut RegisterState) {
+    let mut this = lock_scheduler();
+    this.schedule(state);
+}
+
+unsafe extern "sysv64" fn tlb_flush_handler(_state: &mut RegisterState) {
+    flush_tlb();
+}
+
+/// Drops the user mappings cached by this CPU and acknowledges every shootdown issued so far.
+fn flush_tlb() {
+    let gen = TLB_GEN.load(Ordering::Acquire);
+    unsafe {
+        core::arch::asm!(
+            "mov {0}, cr3",
+            "mov cr3, {0}",
+            out(reg) _,
+            options(nostack, preserves_flags),
+        );
+    }
+    current().tlb_gen.fetch_max(gen, Ordering::Release);
+}
+
+/// Locks the scheduler. Interrupts are usually off here, and the holder may be waiting on a TLB
+/// shootdown, so pending ones are flushed while spinning.
+pub fn lock_scheduler() -> spin::MutexGuard<'static, Scheduler> {
+    let scheduler = unsafe { (*SYS_STATE.get()).scheduler.as_ref().unwrap() };
+    loop {
+        if let Some(v) = scheduler.try_lock() {
+            return v;
+        }
+        if current().tlb_gen.load(Ordering::Acquire) < TLB_GEN.load(Ordering::Acquire) {
+            flush_tlb();
+        }
+        core::hint::spin_loop();
+    }
+}
+
+fn send_ipi(id: usize, vector: u8) {
+    let state = unsafe { &*SYS_STATE.get() };
+    if id == current().id {
+        return;
+    }
+    state.lapic.as_ref().unwrap().send_ipi(
+        InterruptCommand::new()
+            .with_vector(vector)
+            .with_dest(state.cpus[id].apic_id),
+    );
+}
+
+/// Makes CPU `id` go through the scheduler, e.g. because its thread died.
+pub fn kick(id: usize) {
+    send_ipi(id, RESCHEDULE_VECTOR);
+}
+
+/// Runs the timer interrupt on CPU `id` early, so it picks up a new deadline.
+pub fn poke_timer(id: usize) {
+    send_ipi(id, TIMER_VECTOR);
+}
+
+/// Drops stale mappings of process `pid` from the TLBs of the other CPUs running it, and waits
+/// until they all have, so the frames behind the mappings can be reused. Must be called with the
+/// scheduler locked, which keeps other CPUs from switching to `pid` meanwhile.
+pub fn shootdown(pid: u64) {
+    let state = unsafe { &*SYS_STATE.get() };
+    let this = current().id;
+    let gen = TLB_GEN.fetch_add(1, Ordering::AcqRel) + 1;
+    let targets = || {
+        state
+            .cpus
+            .iter()
+            .filter(move |v| v.id != this && v.current_pid == Some(pid))
+    };
+
+    for cpu in targets() {
+        send_ipi(cpu.id, TLB_FLUSH_VECTOR);
+    }
+    while targets().any(|v| v.tlb_gen.load(Ordering::Acquire) < gen) {
+        core::hint::spin_loop();
+    }
+}
+
+extern "sysv64" fn ap_main(cpu: &'static mut Cpu) -> ! {
+    let state = unsafe { &mut *SYS_STATE.get() };
+    unsafe {
+        state.pml4.as_ref().unwrap().lock().set_cr3();
+        let (cr0, cr4, efer) = (*BSP_REGS.get()).unwrap();
+        cr0.write();
+        cr4.write();
+        efer.write();
+        crate::system::vmm::init_pat();
+        cpu.load();
+        crate::interrupts::idt::IDTR.load();
+    }
+    crate::system::fpu::init();
+    let lapic = state.lapic.as_ref().unwrap();
+    lapic.init(false);
+    crate::system::tasking::userland::init_cpu();
+    lapic.init_timer();
+
+    {
+        let mut scheduler = lock_scheduler();
+        let id = cpu.id;
+        scheduler.add_cpu(id);
+        // The BSP numbers the next CPU after this one once it sees it online.
+        state.cpus.push(cpu);
+        state.cpus[id].online.store(true, Ordering::Release);
+    }
+
+    Scheduler::unmask();
+    crate::hlt_loop!();
+}
+
+/// Usable page below 1 MiB for the trampoline. The PMM keeps away from the first 2 MiB, so this
+/// does not need to go through it.
+fn trampoline_page(mmap: &[MemoryEntry]) -> Option<u64> {
+    mmap.iter()
+        .filter_map(|v| match v {
+            MemoryEntry::Usable(v) => Some(v),
+            _ => None,
+        })
+        .filter_map(|v| {
+            let end = (v.base + v.length).min(0x10_0000) & !0xFFF;
+            let start = v.base.max(0x1000).next_multiple_of(0x1000);
+            (end > start).then(|| end - 0x1000)
+        })
+        .max()
+}
+
+/// Starts the enabled APs listed in the MADT one after the other.
+pub fn start_aps(state: &mut SystemState, mmap: &[MemoryEntry]) {
+    let lapic = state.lapic.as_ref().unwrap();
+    let bsp_apic_id = lapic.id();
+    let targets: Vec<_> = state
+        .madt
+        .as_ref()
+        .unwrap()
+        .lock()
+        .proc_lapics
+        .iter()
+        .filter(|v| { v.flags }.enabled() && v.apic_id != bsp_apic_id)
+        .map(|v| v.apic_id)
+        .collect();
+    if targets.is_empty() {
+        return;
+    }
+
+    let tables = unsafe {
+        state
+            .pmm
+            .as_ref()
+            .unwrap()
+            .lock()
+            .alloc_aligned(3, 1, 0x1_0000_0000)
+    };
+    let (Some(tramp_page), Some(tables)) = (trampoline_page(mmap), tables) else {
+        error!("No low memory for the AP trampoline, staying on one CPU");
+        return;
+    };
+
+    // The trampoline runs with a copy of the kernel PML4 below 4 GiB that also identity maps
+    // the first 2 MiB.
+    let tables = tables as u64;
+    unsafe {
+        let pml4 = (tables + amd64::paging::PHYS_VIRT_OFFSET) as *mut u64;
+        let pdpt = pml4.add(512);
+        let pd = pdpt.add(512);
+        let kern_pml4 = &**state.pml4.as_ref().unwrap().lock() as *const _ as *const u64;
+        pml4.copy_from_nonoverlapping(kern_pml4, 512);
+        pdpt.write_bytes(0, 512);
+        pd.write_bytes(0, 512);
+        pml4.write((tables + 0x1000) | 0b11);
+        pdpt.write((tables + 0x2000) | 0b11);
+        // Present, writable, huge.
+        pd.write(0x83);
+        *BSP_REGS.get() = Some((
+            ControlReg0::read(),
+            ControlReg4::read(),
+            ExtendedFeatureEnableReg::read(),
+        ));
+    }
+    let trampoline = unsafe { trampoline::Trampoline::install(tramp_page, tables) };
+
+    state.cpus.reserve(targets.len());
+    let hpet = state.hpet.as_ref().unwrap();
+    let mut timed_out = false;
+    for apic_id in targets {
+        let cpu = Cpu::new(state.cpus.len(), apic_id);
+        let online = &cpu.online as *const AtomicBool;
+        let stack: &'static mut [u8] = vec![0; AP_BOOT_STACK_SIZE].leak();
+        unsafe {
+            trampoline.prepare(
+                ap_main as usize as u64,
+                stack.as_ptr() as u64 + stack.len() as u64,
+                cpu as *mut Cpu as u64,
+            );
+        }
+
+        lapic.send_ipi(
+            InterruptCommand::new()
+                .with_delivery_mode(DeliveryMode::Init)
+                .with_level_trigger(true)
+                .with_assert(true)
+                .with_dest(apic_id),
+        );
+        hpet.sleep(10);
+        for _ in 0..2 {
+            lapic.send_ipi(
+                InterruptCommand::new()
+                    .with_vector(trampoline.vector())
+                    .with_delivery_mode(DeliveryMode::StartUp)
+                    .with_dest(apic_id),
+            );
+            hpet.sleep(1);
+        }
+
+        let deadline = crate::timer::monotonic_ns() + 100_000_000;
+        while !unsafe { &*online }.load(Ordering::Acquire) {
+            if crate::timer::monotonic_ns() > deadline {
+                timed_out = true;
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        if timed_out {
+            // It might still wake up and run the trampoline, so leave everything in place.
+            error!("CPU with APIC ID {apic_id} did not come online");
+            break;
+        }
+    }
+
+    if !timed_out {
+        unsafe {
+            state.pmm.as_ref().unwrap().lock().free(tables as *mut _, 3);
+        }
+    }
+    info!("{} CPUs online", state.cpus.len());
+}
+
+/// Publishes one child of the root OSDT entry per online CPU.
+pub fn publish(state: &SystemState) {
+    let mut dt_index = state.dt_index.as_ref().unwrap().write();
+    let mut dt_id_gen = state.dt_id_gen.as_ref().unwrap().lock();
+    for cpu in &state.cpus {
+        let id = dt_id_gen.next();
+        let entry = OSDTEntry {
+            id,
+            parent: Some(0u64.into()),
+            properties: HashMap::from([
+                (OSDTENTRY_NAME_KEY.into(), "CPU".into()),
+                ("ID".into(), (cpu.id as u64).into()),
+                ("APICID".into(), u64::from(cpu.apic_id).into()),
+            ]),
+            ..Default::default()
+        };
+        dt_index.get(&0).unwrap().lock().children.push(id.into());
+        dt_index.insert(id, entry.into());
+    }
+}
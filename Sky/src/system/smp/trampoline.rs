@@ -0,0 +1,140 @@
+// Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+//! Real mode entry point of the APs, copied below 1 MiB and started with a SIPI.
+//! It goes straight to long mode with a temporary PML4 that identity maps the first 2 MiB, then
+//! jumps to the higher half entry with the parameters the BSP patched in.
+
+core::arch::global_asm!(
+    ".pushsection .text.ap_trampoline, \"ax\"",
+    ".code16",
+    ".global ap_tramp_start",
+    "ap_tramp_start:",
+    "    cli",
+    "    cld",
+    "    mov %cs, %ax",
+    "    mov %ax, %ds",
+    "    lgdtl ap_tramp_gdtr - ap_tramp_start",
+    // PAE
+    "    mov %cr4, %eax",
+    "    or $0x20, %eax",
+    "    mov %eax, %cr4",
+    "    mov ap_tramp_cr3 - ap_tramp_start, %eax",
+    "    mov %eax, %cr3",
+    // LME and NXE
+    "    mov $0xC0000080, %ecx",
+    "    rdmsr",
+    "    or $0x900, %eax",
+    "    wrmsr",
+    // PE, WP and PG
+    "    mov %cr0, %eax",
+    "    or $0x80010001, %eax",
+    "    mov %eax, %cr0",
+    "    .byte 0x66, 0xEA",
+    ".global ap_tramp_jump_target",
+    "ap_tramp_jump_target:",
+    "    .long 0",
+    "    .word 0x08",
+    ".code64",
+    ".global ap_tramp_long_mode",
+    "ap_tramp_long_mode:",
+    "    mov $0x10, %ax",
+    "    mov %ax, %ds",
+    "    mov %ax, %es",
+    "    mov %ax, %ss",
+    "    xor %ax, %ax",
+    "    mov %ax, %fs",
+    "    mov %ax, %gs",
+    "    mov ap_tramp_stack(%rip), %rsp",
+    "    mov ap_tramp_cpu(%rip), %rdi",
+    "    mov ap_tramp_entry(%rip), %rax",
+    "    jmp *%rax",
+    ".balign 8",
+    ".global ap_tramp_gdt",
+    "ap_tramp_gdt:",
+    "    .quad 0",
+    "    .quad 0x00AF9A000000FFFF",
+    "    .quad 0x00CF92000000FFFF",
+    "ap_tramp_gdtr:",
+    "    .word 23",
+    ".global ap_tramp_gdt_base",
+    "ap_tramp_gdt_base:",
+    "    .long 0",
+    ".balign 8",
+    ".global ap_tramp_cr3",
+    "ap_tramp_cr3:",
+    "    .quad 0",
+    ".global ap_tramp_entry",
+    "ap_tramp_entry:",
+    "    .quad 0",
+    ".global ap_tramp_stack",
+    "ap_tramp_stack:",
+    "    .quad 0",
+    ".global ap_tramp_cpu",
+    "ap_tramp_cpu:",
+    "    .quad 0",
+    ".global ap_tramp_end",
+    "ap_tramp_end:",
+    ".popsection",
+    options(att_syntax)
+);
+
+extern "C" {
+    static ap_tramp_start: u8;
+    static ap_tramp_jump_target: u8;
+    static ap_tramp_long_mode: u8;
+    static ap_tramp_gdt: u8;
+    static ap_tramp_gdt_base: u8;
+    static ap_tramp_cr3: u8;
+    static ap_tramp_entry: u8;
+    static ap_tramp_stack: u8;
+    static ap_tramp_cpu: u8;
+    static ap_tramp_end: u8;
+}
+
+fn offset_of(label: &u8) -> usize {
+    label as *const u8 as usize - unsafe { core::ptr::addr_of!(ap_tramp_start) } as usize
+}
+
+/// A copy of the trampoline at a physical address below 1 MiB.
+pub struct Trampoline {
+    phys: u64,
+}
+
+impl Trampoline {
+    /// Copies the trampoline to `phys`, which must be page aligned and below 1 MiB.
+    /// `cr3` must be below 4 GiB and identity map the page.
+    pub unsafe fn install(phys: u64, cr3: u64) -> Self {
+        let len = offset_of(&ap_tramp_end);
+        let dst = (phys + amd64::paging::PHYS_VIRT_OFFSET) as *mut u8;
+        core::ptr::copy_nonoverlapping(core::ptr::addr_of!(ap_tramp_start), dst, len);
+
+        let this = Self { phys };
+        this.write::<u32>(
+            &ap_tramp_jump_target,
+            (phys + offset_of(&ap_tramp_long_mode) as u64) as u32,
+        );
+        this.write::<u32>(
+            &ap_tramp_gdt_base,
+            (phys + offset_of(&ap_tramp_gdt) as u64) as u32,
+        );
+        this.write(&ap_tramp_cr3, cr3);
+        this
+    }
+
+    unsafe fn write<T>(&self, label: &u8, value: T) {
+        ((self.phys + amd64::paging::PHYS_VIRT_OFFSET + offset_of(label) as u64) as *mut T)
+            .write_unaligned(value);
+    }
+
+    /// Sets up the next AP to start at `entry` on `stack`, with `cpu` as its argument.
+    pub unsafe fn prepare(&self, entry: u64, stack: u64, cpu: u64) {
+        self.write(&ap_tramp_entry, entry);
+        self.write(&ap_tramp_stack, stack);
+        self.write(&ap_tramp_cpu, cpu);
+    }
+
+    /// The SIPI vector, the page number of the trampoline.
+    pub const fn vector(&self) -> u8 {
+        (self.phys >> 12) as u8
+    }
+}
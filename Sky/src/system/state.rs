@@ -6,11 +6,12 @@ use core::cell::SyncUnsafeCell;
 use hashbrown::HashMap;
 
 use super::{
-    pmm::BitmapAllocator, tasking::scheduler::Scheduler, terminal::Terminal, vmm::PageTableLvl4,
+    pmm::BuddyAllocator, tasking::scheduler::Scheduler, terminal::Terminal, vmm::PageTableLvl4,
 };
 use crate::{
     acpi::{apic::LocalAPIC, madt::MADTData, ACPIState},
     incr_id::IncrementalIDGen,
+    timer::hpet::Hpet,
 };
 
 pub static SYS_STATE: SyncUnsafeCell<SystemState> = SyncUnsafeCell::new(SystemState::new());
@@ -27,12 +28,16 @@ pub struct SystemState {
     pub kern_symbols: Option<&'static [skyliftkit::KernSymbol]>,
     pub verbose: bool,
     pub serial_enabled: bool,
-    pub pmm: Option<spin::Mutex<BitmapAllocator>>,
+    pub pmm: Option<spin::Mutex<BuddyAllocator>>,
     pub pml4: Option<spin::Mutex<Box<PageTableLvl4>>>,
     pub terminal: Option<Terminal>,
     pub acpi: Option<ACPIState>,
     pub madt: Option<spin::Mutex<MADTData>>,
     pub lapic: Option<LocalAPIC>,
+    pub fpu: Option<super::fpu::FpuInfo>,
+    /// Online CPUs, indexed by their ID.
+    pub cpus: Vec<&'static mut super::smp::Cpu>,
+    pub hpet: Option<Hpet>,
     pub scheduler: Option<spin::Mutex<Scheduler>>,
     pub interrupt_context: Option<super::RegisterState>,
     pub in_panic: core::sync::atomic::AtomicBool,
@@ -60,6 +65,9 @@ impl SystemState {
             acpi: None,
             madt: None,
             lapic: None,
+            fpu: None,
+            cpus: Vec::new(),
+            hpet: None,
             scheduler: None,
             interrupt_context: None,
             in_panic: core::sync::atomic::AtomicBool::new(false),
@@ -2,22 +2,27 @@
 
 use alloc::{boxed::Box, collections::VecDeque, string::String, vec::Vec};
 
-use amd64::paging::PageTableFlags;
+use amd64::paging::{PageTableFlags, PAGE_SIZE, PHYS_VIRT_OFFSET};
 use hashbrown::{HashMap, HashSet};
-use skykit::msg::Message;
+use skykit::{caps::SKCapabilities, msg::Message, syscall::MmioCaching};
 
 use super::gdt::{PrivilegeLevel, SegmentSelector};
 
 pub mod scheduler;
 pub mod userland;
+pub mod vma;
 
 pub const STACK_SIZE: u64 = 0x14000;
+/// Nanoseconds a thread may run before others of its priority get a turn.
+pub const QUANTUM_NS: u64 = 10_000_000;
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum ThreadState {
     Active,
     Inactive,
     Suspended,
+    /// Its process was torn down while it ran on another CPU.
+    Dying,
 }
 
 impl ThreadState {
@@ -30,6 +35,42 @@ impl ThreadState {
     pub fn is_inactive(&self) -> bool {
         *self == Self::Inactive
     }
+
+    #[inline]
+    pub fn is_active(&self) -> bool {
+        *self == Self::Active
+    }
+
+    #[inline]
+    pub fn is_dying(&self) -> bool {
+        *self == Self::Dying
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageWait {
+    /// Only accept messages from this PID, 0 being the kernel.
+    pub from: Option<u64>,
+    /// Monotonic nanosecond time at which the wait gives up, if any.
+    pub deadline: Option<u64>,
+}
+
+impl MessageWait {
+    #[inline]
+    pub fn accepts(&self, pid: u64) -> bool {
+        self.from.is_none_or(|v| v == pid)
+    }
+}
+
+/// Initialisation image of the executable's `PT_TLS` segment.
+#[derive(Debug, Clone, Copy)]
+pub struct TlsTemplate {
+    /// User address of the initialised part.
+    pub addr: u64,
+    pub file_size: u64,
+    /// Includes the zero-initialised part.
+    pub mem_size: u64,
+    pub align: u64,
 }
 
 #[derive(Debug)]
@@ -37,10 +78,25 @@ pub struct Thread {
     pub id: u64,
     pub pid: u64,
     pub state: ThreadState,
+    /// CPU whose run queues the thread goes back to.
+    pub cpu: usize,
+    pub priority: u8,
+    /// Monotonic nanosecond time at which the thread's time slice runs out.
+    pub slice_end: u64,
     pub regs: super::RegisterState,
+    pub ext_state: super::fpu::ExtendedState,
     pub fs_base: usize,
     pub gs_base: usize,
     pub stack_addr: u64,
+    /// Allocation holding this thread's copy of the TLS template.
+    pub tls_addr: Option<u64>,
+    pub msg_wait: Option<MessageWait>,
+    /// Thread of the same process this one waits to exit.
+    pub join_wait: Option<u64>,
+    /// Physical address of the futex word this thread sleeps on until woken through it.
+    pub futex_wait: Option<u64>,
+    /// Monotonic nanosecond deadline this thread sleeps until.
+    pub sleep_until: Option<u64>,
 }
 
 impl Thread {
@@ -50,56 +106,166 @@ impl Thread {
             id,
             pid,
             state: ThreadState::Inactive,
+            cpu: 0,
+            priority: skykit::priority::DEFAULT,
+            slice_end: 0,
             regs: super::RegisterState {
                 rip,
-                cs: SegmentSelector::new(3, PrivilegeLevel::User).into(),
+                cs: SegmentSelector::new(4, PrivilegeLevel::User).into(),
                 rflags: 0x202,
                 rsp: stack_addr + STACK_SIZE,
-                ss: SegmentSelector::new(4, PrivilegeLevel::User).into(),
+                ss: SegmentSelector::new(3, PrivilegeLevel::User).into(),
                 ..Default::default()
             },
+            ext_state: super::fpu::ExtendedState::new(),
             fs_base: 0,
             gs_base: 0,
             stack_addr,
+            tls_addr: None,
+            msg_wait: None,
+            join_wait: None,
+            futex_wait: None,
+            sleep_until: None,
         }
     }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AllocationType {
-    Kernel,
     Readable,
     Writable,
+    /// Zero-filled memory whose pages are only backed once touched.
+    Lazy,
+    /// Mapping of a [`SharedMemory`] object; the pages belong to the object, not the process.
+    Shared {
+        id: u64,
+        writable: bool,
+    },
+    /// Device registers; the pages are not managed by the PMM.
+    Mmio(MmioCaching),
+    /// Physically contiguous buffer a device may access; it is pinned at its physical address.
+    Dma,
+}
+
+impl AllocationType {
+    #[inline]
+    pub const fn is_writable(&self) -> bool {
+        matches!(
+            self,
+            Self::Writable
+                | Self::Lazy
+                | Self::Shared { writable: true, .. }
+                | Self::Mmio(_)
+                | Self::Dma
+        )
+    }
+
+    #[inline]
+    pub const fn is_shared(&self) -> bool {
+        matches!(self, Self::Shared { .. })
+    }
+
+    #[inline]
+    pub const fn is_mmio(&self) -> bool {
+        matches!(self, Self::Mmio(_))
+    }
+
+    /// Index into the PAT set up by [`crate::system::vmm::PageTableLvl4::init`].
+    #[inline]
+    pub const fn pat_index(&self) -> u8 {
+        match self {
+            Self::Mmio(MmioCaching::Uncacheable) => 4,
+            Self::Mmio(MmioCaching::WriteCombining) => 2,
+            _ => 0,
+        }
+    }
+}
+
+/// Why a page of a process could not be backed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultError {
+    /// No allocation covers the address.
+    Unallocated,
+    OutOfMemory,
+}
+
+#[derive(Debug)]
+pub struct SharedMemory {
+    pub owner: u64,
+    pub phys: u64,
+    pub size: u64,
+    /// PIDs allowed to map the object, and whether they may write to it.
+    pub grants: HashMap<u64, bool>,
+}
+
+/// Delivers [`skykit::msg::KernelMessage::TimerFired`] to its owner.
+#[derive(Debug)]
+pub struct UserTimer {
+    pub owner: u64,
+    /// Monotonic nanoseconds.
+    pub deadline: u64,
+    pub period: Option<u64>,
 }
 
 #[derive(Debug)]
 pub struct Process {
     pub id: u64,
+    /// Process allowed to grant capabilities to this one.
+    pub parent: Option<u64>,
     pub path: String,
+    /// Set once the executable is placed.
     pub image_base: u64,
     pub cr3: spin::Mutex<Box<userland::page_table::UserPML4>>,
+    pub vmas: vma::VmaTree,
     pub messages: VecDeque<Message>,
     pub allocations: HashMap<u64, (u64, AllocationType)>,
     pub msg_id_to_addr: HashMap<u64, u64>,
     pub addr_to_msg_id: HashMap<u64, u64>,
+    /// Where messages from other processes are mapped in, by ID.
+    pub msg_mappings: HashMap<u64, u64>,
     pub thread_ids: HashSet<u64>,
+    /// Exit values of threads nobody has joined yet. Their IDs stay reserved until then.
+    pub exited_threads: HashMap<u64, u64>,
+    /// Threads nobody is going to join. Their IDs are freed as soon as they exit.
+    pub detached_threads: HashSet<u64>,
     pub alloc_lock: spin::Mutex<()>,
+    pub caps: SKCapabilities,
+    /// Ports the process may access with `in`/`out` directly, in the TSS format.
+    pub io_bitmap: Option<Box<[u8]>>,
+    pub tls: Option<TlsTemplate>,
 }
 
 impl Process {
     #[inline]
-    pub fn new(id: u64, path: String, image_base: u64) -> Self {
+    pub fn new(id: u64, parent: Option<u64>, path: String, caps: SKCapabilities) -> Self {
         Self {
             id,
+            parent,
             path,
-            image_base,
-            cr3: Box::new(userland::page_table::UserPML4::new(id)).into(),
+            image_base: 0,
+            cr3: Box::new(userland::page_table::UserPML4::new()).into(),
+            vmas: vma::VmaTree::new(),
             messages: VecDeque::new(),
             allocations: HashMap::new(),
             msg_id_to_addr: HashMap::new(),
             addr_to_msg_id: HashMap::new(),
+            msg_mappings: HashMap::new(),
             thread_ids: HashSet::new(),
+            exited_threads: HashMap::new(),
+            detached_threads: HashSet::new(),
             alloc_lock: spin::Mutex::new(()),
+            caps,
+            io_bitmap: None,
+            tls: None,
+        }
+    }
+
+    pub fn allow_direct_ports(&mut self, first: u16, last: u16) {
+        let bitmap = self
+            .io_bitmap
+            .get_or_insert_with(|| vec![0xFF; crate::system::tss::IO_BITMAP_SIZE].into());
+        for port in first..=last {
+            bitmap[usize::from(port / 8)] &= !(1 << (port % 8));
         }
     }
 
@@ -110,61 +276,176 @@ impl Process {
         thread
     }
 
-    pub fn track_alloc(&mut self, addr: u64, size: u64, ty: AllocationType) {
-        let _lock = self.alloc_lock.lock();
+    /// Gives `thread` its own copy of the TLS template, laid out below the thread control block
+    /// as the x86-64 ABI expects, and points its FS base at the latter.
+    /// Returns `false` if out of memory.
+    pub fn setup_tls(&mut self, thread: &mut Thread) -> bool {
+        let Some(tls) = self.tls else {
+            return true;
+        };
+        let offset = tls.mem_size.next_multiple_of(tls.align);
+        let Some((addr, _)) = self.allocate(vma::Area::Heap, offset + 8) else {
+            return false;
+        };
 
-        if self.allocations.contains_key(&addr) {
-            panic!("PID {}: Address {addr:#X} already allocated", self.id);
+        let to_kern = |v: u64| self.kern_addr(v) as *mut u8;
+        let tcb = addr + offset;
+        unsafe {
+            to_kern(addr).write_bytes(0, offset as _);
+            to_kern(addr).copy_from_nonoverlapping(to_kern(tls.addr), tls.file_size as _);
+            // The TCB starts with a pointer to itself, which is how `fs:0` finds it.
+            to_kern(tcb).cast::<u64>().write(tcb);
         }
+        thread.tls_addr = Some(addr);
+        thread.fs_base = tcb as _;
+        true
+    }
+
+    /// Translates a user address to the kernel's mapping of its page. Within a physically
+    /// contiguous allocation, offsets carry over.
+    pub fn kern_addr(&self, addr: u64) -> u64 {
+        let phys = unsafe { self.cr3.lock().virt_to_phys(addr) };
+        phys.unwrap_or_else(|| panic!("PID {}: Address {addr:#X} not mapped", self.id))
+            + PHYS_VIRT_OFFSET
+    }
 
-        let page_count = (size + 0xFFF) / 0x1000;
+    /// Maps `size` bytes of physical memory from `phys` at a free range of `area`.
+    /// Returns the user address, or [`None`] if the area is full.
+    pub fn track_alloc(
+        &mut self,
+        area: vma::Area,
+        phys: u64,
+        size: u64,
+        ty: AllocationType,
+    ) -> Option<u64> {
+        let _lock = self.alloc_lock.lock();
+
+        let page_count = size.div_ceil(PAGE_SIZE);
 
         assert!(
-            unsafe {
-                (*crate::system::state::SYS_STATE.get())
-                    .pmm
-                    .as_ref()
-                    .unwrap()
-                    .lock()
-                    .is_allocated((addr - skykit::USER_VIRT_OFFSET) as *mut _, page_count)
-            },
-            "PID {}: Address {addr:#X} not allocated",
+            ty.is_mmio()
+                || unsafe {
+                    (*crate::system::state::SYS_STATE.get())
+                        .pmm
+                        .as_ref()
+                        .unwrap()
+                        .lock()
+                        .is_allocated(phys as *mut _, page_count)
+                },
+            "PID {}: Address {phys:#X} not allocated",
             self.id,
         );
 
+        let addr = self.vmas.reserve(area, size)?;
         trace!(
-            "PID {}: Tracking {addr:#X} ({ty:?}, {size} byte{}, {page_count} page{}, will map: {})",
+            "PID {}: Tracking {addr:#X} ({ty:?}, {area:?}, {size} byte{}, {page_count} page{})",
             self.id,
             if size > 1 { "s" } else { "" },
             if page_count > 1 { "s" } else { "" },
-            ty != AllocationType::Kernel
         );
         self.allocations.insert(addr, (size, ty));
 
-        if ty == AllocationType::Kernel {
-            return;
-        }
-
         unsafe {
             drop(_lock);
             self.cr3.lock().map(
                 addr,
-                addr - skykit::USER_VIRT_OFFSET,
+                phys,
                 page_count,
                 PageTableFlags::new_present()
-                    .with_writable(ty == AllocationType::Writable)
-                    .with_user(true),
+                    .with_writable(ty.is_writable())
+                    .with_user(true)
+                    .with_pat_entry(ty.pat_index()),
             );
         }
+        Some(addr)
+    }
+
+    /// Hands over pages allocated by the kernel, e.g. from [`crate::system::allocator::alloc_pages`].
+    pub fn track_kernelside_alloc(&mut self, addr: u64, size: u64) -> Option<u64> {
+        self.track_alloc(
+            vma::Area::Heap,
+            addr - PHYS_VIRT_OFFSET,
+            size,
+            AllocationType::Readable,
+        )
+    }
+
+    /// Changes the type of the allocation at `addr` to `ty`, which needs the same backing.
+    pub fn retype_alloc(&mut self, addr: u64, ty: AllocationType) {
+        let _lock = self.alloc_lock.lock();
+
+        let (size, old) = self.allocations.get_mut(&addr).unwrap();
+        trace!("PID {}: Retyping {addr:#X} ({old:?} to {ty:?})", self.id);
+        *old = ty;
+        let page_count = size.div_ceil(PAGE_SIZE);
+
+        drop(_lock);
+        let mut cr3 = self.cr3.lock();
+        for virt in (0..page_count).map(|i| addr + i * PAGE_SIZE) {
+            unsafe {
+                let phys = cr3.virt_to_phys(virt).unwrap();
+                cr3.unmap(virt, 1);
+                cr3.map(
+                    virt,
+                    phys,
+                    1,
+                    PageTableFlags::new_present()
+                        .with_writable(ty.is_writable())
+                        .with_user(true)
+                        .with_pat_entry(ty.pat_index()),
+                );
+            }
+        }
+        drop(cr3);
+        crate::system::smp::shootdown(self.id);
+    }
+
+    /// Maps the pages of another process's allocation read-only at a free range of the heap.
+    pub fn map_foreign(&mut self, frames: &[u64]) -> Option<u64> {
+        let _lock = self.alloc_lock.lock();
+
+        let addr = self
+            .vmas
+            .reserve(vma::Area::Heap, frames.len() as u64 * PAGE_SIZE)?;
+        drop(_lock);
+        let mut cr3 = self.cr3.lock();
+        for (i, &phys) in frames.iter().enumerate() {
+            unsafe {
+                cr3.map(
+                    addr + i as u64 * PAGE_SIZE,
+                    phys,
+                    1,
+                    PageTableFlags::new_present().with_user(true),
+                );
+            }
+        }
+        Some(addr)
     }
 
-    pub fn track_kernelside_alloc(&mut self, addr: u64, size: u64) -> u64 {
+    pub fn unmap_foreign(&mut self, addr: u64) {
         let _lock = self.alloc_lock.lock();
 
-        let addr = addr - amd64::paging::PHYS_VIRT_OFFSET + skykit::USER_VIRT_OFFSET;
+        let size = self.vmas.release(addr).unwrap();
         drop(_lock);
-        self.track_alloc(addr, size, AllocationType::Readable);
-        addr
+        unsafe { self.cr3.lock().unmap(addr, size / PAGE_SIZE) }
+        crate::system::smp::shootdown(self.id);
+    }
+
+    /// Physical address of every page of the allocation at `addr`.
+    pub fn frames_of(&self, addr: u64) -> Vec<u64> {
+        let (size, _) = self.allocations[&addr];
+        let mut cr3 = self.cr3.lock();
+        (0..size.div_ceil(PAGE_SIZE))
+            .map(|i| unsafe { cr3.virt_to_phys(addr + i * PAGE_SIZE).unwrap() })
+            .collect()
+    }
+
+    /// Finds where the process has shared memory object `id` mapped.
+    pub fn shm_mapping(&self, id: u64) -> Option<u64> {
+        self.allocations
+            .iter()
+            .find(|(_, (_, ty))| matches!(ty, AllocationType::Shared { id: v, .. } if *v == id))
+            .map(|(&k, _)| k)
     }
 
     pub fn region_is_valid(&self, addr: u64, size: u64) -> bool {
@@ -191,24 +472,39 @@ impl Process {
         let _lock = self.alloc_lock.lock();
 
         let (size, ty) = self.allocations.remove(&addr).unwrap();
-        let page_count = (size + 0xFFF) / 0x1000;
+        self.vmas.release(addr).unwrap();
+        let page_count = size.div_ceil(PAGE_SIZE);
         trace!(
             "PID {}: Freeing {addr:#X} ({ty:?}, {page_count} pages, {size} bytes)",
             self.id
         );
 
-        unsafe {
+        drop(_lock);
+        let mut cr3 = self.cr3.lock();
+        let mut frames = Vec::new();
+        for virt in (0..page_count).map(|i| addr + i * PAGE_SIZE) {
+            // Lazily backed pages may never have been touched.
+            let Some(phys) = (unsafe { cr3.virt_to_phys(virt) }) else {
+                continue;
+            };
+            if !ty.is_shared() && !ty.is_mmio() {
+                frames.push(phys);
+            }
+            unsafe { cr3.unmap(virt, 1) }
+        }
+        drop(cr3);
+
+        // Other CPUs may still reach the frames through their TLBs until the shootdown is over.
+        crate::system::smp::shootdown(self.id);
+        let mut pmm = unsafe {
             (*crate::system::state::SYS_STATE.get())
                 .pmm
                 .as_ref()
                 .unwrap()
                 .lock()
-                .free((addr - skykit::USER_VIRT_OFFSET) as *mut _, page_count);
-        }
-
-        if ty != AllocationType::Kernel {
-            drop(_lock);
-            unsafe { self.cr3.lock().unmap(addr, page_count) }
+        };
+        for phys in frames {
+            unsafe { pmm.free(phys as *mut _, 1) }
         }
     }
 
@@ -246,27 +542,120 @@ impl Process {
         self.addr_to_msg_id.contains_key(&addr)
     }
 
-    pub fn allocate(&mut self, size: u64) -> (u64, u64) {
-        let _lock = self.alloc_lock.lock();
-
-        let page_count = (size + 0xFFF) / 0x1000;
+    pub fn allocate(&mut self, area: vma::Area, size: u64) -> Option<(u64, u64)> {
+        let page_count = size.div_ceil(PAGE_SIZE);
         trace!(
             "PID {}: Allocating {page_count} pages ({size} bytes)",
             self.id
         );
-        let addr = unsafe {
+        let pmm = unsafe {
+            (*crate::system::state::SYS_STATE.get())
+                .pmm
+                .as_ref()
+                .unwrap()
+        };
+        let phys = unsafe { pmm.lock().alloc(page_count)? } as u64;
+        let Some(virt) = self.track_alloc(area, phys, size, AllocationType::Writable) else {
+            unsafe { pmm.lock().free(phys as *mut _, page_count) }
+            return None;
+        };
+        Some((virt, page_count))
+    }
+
+    /// Reserves `size` bytes of zero-filled memory whose pages are backed on first access.
+    pub fn allocate_lazy(&mut self, size: u64) -> Option<u64> {
+        let _lock = self.alloc_lock.lock();
+
+        let addr = self.vmas.reserve(vma::Area::Heap, size)?;
+        trace!(
+            "PID {}: Reserving {addr:#X} ({} pages, {size} bytes)",
+            self.id,
+            size.div_ceil(PAGE_SIZE)
+        );
+        self.allocations.insert(addr, (size, AllocationType::Lazy));
+        Some(addr)
+    }
+
+    /// Makes sure the page holding `addr` is mapped, backing it with a zeroed frame if it belongs
+    /// to a lazily backed allocation.
+    pub fn back_page(&mut self, addr: u64) -> Result<(), FaultError> {
+        let _lock = self.alloc_lock.lock();
+
+        let start = self.vmas.find(addr).ok_or(FaultError::Unallocated)?;
+        let page = addr & !(PAGE_SIZE - 1);
+        let mut cr3 = self.cr3.lock();
+        if unsafe { cr3.virt_to_phys(page) }.is_some() {
+            return Ok(());
+        }
+        if self.allocations.get(&start).map(|v| v.1) != Some(AllocationType::Lazy) {
+            return Err(FaultError::Unallocated);
+        }
+
+        let phys = unsafe {
             (*crate::system::state::SYS_STATE.get())
                 .pmm
                 .as_ref()
                 .unwrap()
                 .lock()
-                .alloc(page_count)
-                .unwrap() as u64
+                .alloc(1)
+                .ok_or(FaultError::OutOfMemory)?
+        } as u64;
+        trace!("PID {}: Backing {page:#X} with {phys:#X}", self.id);
+        unsafe {
+            ((phys + PHYS_VIRT_OFFSET) as *mut u8).write_bytes(0, PAGE_SIZE as _);
+            cr3.map(
+                page,
+                phys,
+                1,
+                PageTableFlags::new_present()
+                    .with_writable(true)
+                    .with_user(true),
+            );
+        }
+        Ok(())
+    }
+
+    /// Checks that the region lies within an allocation and backs its lazily backed pages, so the
+    /// kernel can access it. Returns `false` if it does not or if out of memory.
+    pub fn fault_in(&mut self, addr: u64, size: u64) -> bool {
+        if !self.region_is_valid(addr, size) {
+            return false;
+        }
+        let end = addr + size;
+        let mut page = addr & !(PAGE_SIZE - 1);
+        while page < end {
+            if self.back_page(page).is_err() {
+                return false;
+            }
+            page += PAGE_SIZE;
+        }
+        true
+    }
+
+    /// Allocates physically contiguous pages aligned to `align` bytes, all below `max_addr`.
+    /// Returns the virtual and physical addresses.
+    pub fn allocate_dma(&mut self, size: u64, align: u64, max_addr: u64) -> Option<(u64, u64)> {
+        let page_count = size.div_ceil(PAGE_SIZE);
+        trace!(
+            "PID {}: Allocating {page_count} DMA pages ({size} bytes, {align:#X} alignment, below \
+             {max_addr:#X})",
+            self.id
+        );
+        let pmm = unsafe {
+            (*crate::system::state::SYS_STATE.get())
+                .pmm
+                .as_ref()
+                .unwrap()
         };
-        let virt = addr + skykit::USER_VIRT_OFFSET;
-        drop(_lock);
-        self.track_alloc(virt, size, AllocationType::Writable);
-        (virt, page_count)
+        let phys = unsafe {
+            pmm.lock()
+                .alloc_aligned(page_count, align.div_ceil(PAGE_SIZE), max_addr)?
+        } as u64;
+        let Some(virt) = self.track_alloc(vma::Area::Heap, phys, size, AllocationType::Dma) else {
+            unsafe { pmm.lock().free(phys as *mut _, page_count) }
+            return None;
+        };
+        Some((virt, phys))
     }
 }
 
@@ -1,34 +1,48 @@
 // Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
 
-use alloc::{string::String, vec::Vec};
-use core::{cell::SyncUnsafeCell, ops::ControlFlow};
+use alloc::{collections::VecDeque, string::String, vec::Vec};
+use core::ops::ControlFlow;
 
-use hashbrown::HashMap;
+use amd64::msr::{fs_base::FsBase, kernel_gs_base::KernelGsBase, ModelSpecificReg};
+use hashbrown::{HashMap, HashSet};
 use skykit::{
+    caps::SKCapabilities,
     msg::{KernelMessage, Message},
-    TerminationReason,
+    SkyError, TerminationReason,
 };
 
 use crate::{
     system::{
         gdt::{PrivilegeLevel, SegmentSelector},
-        tasking::AllocationType,
-        tss::TaskSegmentSelector,
+        smp,
+        tasking::{userland::handlers::fail, AllocationType},
         RegisterState,
     },
     timer::Timer,
 };
 
-static TSS: SyncUnsafeCell<TaskSegmentSelector> = SyncUnsafeCell::new(TaskSegmentSelector::new(0));
+type RunQueues = [VecDeque<u64>; skykit::priority::LEVELS as usize];
 
 pub struct Scheduler {
     pub processes: HashMap<u64, super::Process>,
+    /// Torn down processes whose threads are still running on other CPUs.
+    pub dying: HashMap<u64, super::Process>,
+    /// Shared memory owned by dying processes, which they may still be accessing.
+    pub dying_shm: HashMap<u64, Vec<(u64, super::SharedMemory)>>,
     pub threads: HashMap<u64, super::Thread>,
-    pub current_tid: Option<u64>,
-    pub current_pid: Option<u64>,
-    pub kern_stack: Vec<u8>,
+    /// Threads ready to run per CPU and priority, in the order they get their turn.
+    pub run_queues: Vec<RunQueues>,
     pub irq_handlers: HashMap<u8, u64>,
-    pub message_sources: HashMap<u64, u64>,
+    pub services: HashMap<String, u64>,
+    pub service_watchers: HashMap<String, HashSet<u64>>,
+    /// Processes to notify when the PID exits.
+    pub process_watchers: HashMap<u64, HashSet<u64>>,
+    pub shared_mem: HashMap<u64, super::SharedMemory>,
+    pub shm_id_gen: crate::incr_id::IncrementalIDGen,
+    pub timers: HashMap<u64, super::UserTimer>,
+    pub timer_id_gen: crate::incr_id::IncrementalIDGen,
+    /// Sender and receiver of each message not acknowledged yet, with 0 as the kernel.
+    pub message_sources: HashMap<u64, (u64, u64)>,
     pub pid_gen: crate::incr_id::IncrementalIDGen,
     pub tid_gen: crate::incr_id::IncrementalIDGen,
     pub msg_id_gen: crate::incr_id::IncrementalIDGen,
@@ -37,33 +51,12 @@ pub struct Scheduler {
 unsafe extern "sysv64" fn irq_handler(state: &mut RegisterState) {
     let irq = (state.int_num - 0x20) as u8;
     crate::acpi::ioapic::set_irq_mask(irq, true);
-    let mut this = (*crate::system::state::SYS_STATE.get())
-        .scheduler
-        .as_ref()
-        .unwrap()
-        .lock();
+    let mut this = smp::lock_scheduler();
     let pid = this.irq_handlers.get(&irq).copied().unwrap();
-    let s: &mut [u8] = postcard::to_allocvec(&KernelMessage::IRQFired(irq))
-        .unwrap()
-        .leak();
-
-    let virt = this
-        .processes
-        .get_mut(&pid)
-        .unwrap()
-        .track_kernelside_alloc(s.as_ptr() as _, s.len() as _);
-
-    let msg = Message::new(
-        this.msg_id_gen.next(),
-        0,
-        core::slice::from_raw_parts(virt as *const _, s.len() as _),
-    );
-    this.message_sources.insert(msg.id, 0);
-    let process = this.processes.get_mut(&pid).unwrap();
-    process.track_msg(msg.id, virt);
-
-    let tids = process.thread_ids.clone();
-    if super::userland::handlers::msg::handle_new(&mut this, pid, tids, msg).is_break() {
+    if this
+        .send_kernel_msg(pid, &KernelMessage::IRQFired(irq))
+        .is_break()
+    {
         this.schedule(state);
     }
 }
@@ -73,39 +66,20 @@ extern "C" fn idle() {
 }
 
 pub unsafe extern "sysv64" fn schedule(state: &mut RegisterState) {
-    (*crate::system::state::SYS_STATE.get())
-        .scheduler
-        .as_ref()
-        .unwrap()
-        .lock()
-        .schedule(state);
+    let mut this = smp::lock_scheduler();
+    this.tick();
+    if this.should_preempt() {
+        this.schedule(state);
+    } else {
+        this.arm_timer();
+    }
 }
 
 impl Scheduler {
     #[inline]
     pub fn new(timer: &impl Timer) -> Self {
-        let kern_stack = vec![0; 0x14000];
-
-        unsafe {
-            let gdt = &mut *crate::system::gdt::GDT.get();
-            (*TSS.get()) =
-                TaskSegmentSelector::new(kern_stack.as_ptr() as u64 + kern_stack.len() as u64);
-            let tss_addr = TSS.get() as u64;
-            gdt.task_segment.base_low = tss_addr as u16;
-            gdt.task_segment.base_middle = (tss_addr >> 16) as u8;
-            gdt.task_segment.attrs = gdt.task_segment.attrs.with_present(true);
-            gdt.task_segment.base_high = (tss_addr >> 24) as u8;
-            gdt.task_segment.base_upper = (tss_addr >> 32) as u32;
-
-            core::arch::asm!(
-                "ltr ax",
-                in("ax") SegmentSelector::new(5, crate::system::gdt::PrivilegeLevel::Supervisor).0,
-                options(nostack, preserves_flags),
-            );
-        }
-
         let state = unsafe { &mut *crate::system::state::SYS_STATE.get() };
-        state.lapic.as_ref().unwrap().setup_timer(timer);
+        state.lapic.as_mut().unwrap().setup_timer(timer);
 
         crate::interrupts::idt::set_handler(
             128,
@@ -119,11 +93,18 @@ impl Scheduler {
 
         Self {
             processes: HashMap::new(),
+            dying: HashMap::new(),
+            dying_shm: HashMap::new(),
             threads: HashMap::new(),
-            current_tid: None,
-            current_pid: None,
-            kern_stack,
+            run_queues: vec![core::array::from_fn(|_| VecDeque::new())],
             irq_handlers: HashMap::new(),
+            services: HashMap::new(),
+            service_watchers: HashMap::new(),
+            process_watchers: HashMap::new(),
+            shared_mem: HashMap::new(),
+            shm_id_gen: crate::incr_id::IncrementalIDGen::new(),
+            timers: HashMap::new(),
+            timer_id_gen: crate::incr_id::IncrementalIDGen::new(),
             message_sources: HashMap::new(),
             pid_gen: crate::incr_id::IncrementalIDGen::new(),
             tid_gen: crate::incr_id::IncrementalIDGen::new(),
@@ -131,6 +112,13 @@ impl Scheduler {
         }
     }
 
+    /// Gives CPU `id` its run queues. CPUs come online in order.
+    pub fn add_cpu(&mut self, id: usize) {
+        assert_eq!(self.run_queues.len(), id);
+        self.run_queues
+            .push(core::array::from_fn(|_| VecDeque::new()));
+    }
+
     pub fn unmask() {
         crate::sti!();
         let state = unsafe { &*crate::system::state::SYS_STATE.get() };
@@ -139,7 +127,13 @@ impl Scheduler {
         unsafe { core::arch::asm!("int 128", options(nostack, preserves_flags)) }
     }
 
-    pub fn spawn_proc(&mut self, path: String, exec_data: &[u8]) -> &mut super::Thread {
+    pub fn spawn_proc(
+        &mut self,
+        path: String,
+        exec_data: &[u8],
+        parent: Option<u64>,
+        caps: SKCapabilities,
+    ) -> Option<&mut super::Thread> {
         let exec = elf::ElfBytes::<elf::endian::NativeEndian>::minimal_parse(exec_data).unwrap();
         assert_eq!(exec.ehdr.e_type, elf::abi::ET_DYN);
         assert_eq!(exec.ehdr.class, elf::file::Class::ELF64);
@@ -152,7 +146,14 @@ impl Scheduler {
             .map(|v| v.p_vaddr + v.p_memsz)
             .max()
             .unwrap();
-        let data = vec![0; max_vaddr as usize].leak();
+        let pid = self.pid_gen.next();
+        let proc = self
+            .processes
+            .try_insert(pid, super::Process::new(pid, parent, path, caps))
+            .unwrap();
+        unsafe { proc.cr3.lock().map_higher_half() }
+
+        let data = crate::system::allocator::alloc_pages(max_vaddr as _);
         for hdr in exec
             .segments()
             .unwrap()
@@ -165,8 +166,17 @@ impl Scheduler {
             data[ext_vaddr..ext_vaddr + fsz].copy_from_slice(&exec_data[foff..foff + fsz]);
         }
 
-        let virt_addr =
-            data.as_ptr() as u64 - amd64::paging::PHYS_VIRT_OFFSET + skykit::USER_VIRT_OFFSET;
+        let Some(virt_addr) = proc.track_alloc(
+            super::vma::Area::Image,
+            data.as_ptr() as u64 - amd64::paging::PHYS_VIRT_OFFSET,
+            data.len() as _,
+            AllocationType::Writable,
+        ) else {
+            unsafe { crate::system::allocator::free_pages(data) }
+            self.discard_proc(pid);
+            return None;
+        };
+        proc.image_base = virt_addr;
         for v in exec.section_headers().unwrap().iter() {
             let Ok(relas) = exec.section_data_as_relas(&v) else {
                 continue;
@@ -183,48 +193,265 @@ impl Scheduler {
             }
         }
 
-        let pid = self.pid_gen.next();
-        let proc = self
-            .processes
-            .try_insert(pid, super::Process::new(pid, path, virt_addr))
-            .unwrap();
-        unsafe { proc.cr3.lock().map_higher_half() }
-        proc.track_alloc(virt_addr, data.len() as _, AllocationType::Writable);
+        proc.tls = exec
+            .segments()
+            .unwrap()
+            .iter()
+            .find(|v| v.p_type == elf::abi::PT_TLS)
+            .map(|v| {
+                assert!(v.p_align <= 0x1000, "TLS alignment above a page");
+                super::TlsTemplate {
+                    addr: virt_addr + v.p_vaddr,
+                    file_size: v.p_filesz,
+                    mem_size: v.p_memsz,
+                    align: v.p_align.max(1),
+                }
+            });
+        let Some((stack_addr, _)) = proc.allocate(super::vma::Area::Stack, super::STACK_SIZE)
+        else {
+            self.discard_proc(pid);
+            return None;
+        };
         let tid = self.tid_gen.next();
-        let stack_addr = proc.allocate(super::STACK_SIZE).0;
-        let thread = proc.new_thread(tid, virt_addr + exec.ehdr.e_entry, stack_addr);
-        self.threads.try_insert(tid, thread).unwrap()
+        let mut thread = proc.new_thread(tid, virt_addr + exec.ehdr.e_entry, stack_addr);
+        if !proc.setup_tls(&mut thread) {
+            self.discard_proc(pid);
+            return None;
+        }
+        Some(self.add_thread(thread))
+    }
+
+    /// Drops a process that never got to run, e.g. because setting it up failed half-way.
+    pub fn discard_proc(&mut self, pid: u64) {
+        let proc = self.processes.remove(&pid).unwrap();
+        for &tid in &proc.thread_ids {
+            self.threads.remove(&tid);
+            self.tid_gen.free(tid);
+        }
+        for queue in self.run_queues.iter_mut().flatten() {
+            queue.retain(|v| !proc.thread_ids.contains(v));
+        }
+        drop(proc);
+        self.free_pid(pid);
+    }
+
+    /// Places a new thread on the CPU with the fewest ready threads and queues it.
+    pub fn add_thread(&mut self, mut thread: super::Thread) -> &mut super::Thread {
+        let tid = thread.id;
+        thread.cpu = self
+            .run_queues
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, v)| v.iter().map(VecDeque::len).sum::<usize>())
+            .map_or(0, |(i, _)| i);
+        self.threads.try_insert(tid, thread).unwrap();
+        self.make_ready(tid);
+        self.threads.get_mut(&tid).unwrap()
+    }
+
+    /// Queues `tid` on its CPU behind the ready threads of the same priority. The CPU is
+    /// interrupted if it idles or runs something less important, as it may not have a timer armed.
+    pub fn make_ready(&mut self, tid: u64) {
+        let thread = self.threads.get_mut(&tid).unwrap();
+        thread.state = super::ThreadState::Inactive;
+        let (cpu, priority) = (thread.cpu, thread.priority);
+        self.run_queues[cpu][priority as usize].push_back(tid);
+
+        if cpu == smp::current().id {
+            return;
+        }
+        let state = unsafe { &*crate::system::state::SYS_STATE.get() };
+        if state.cpus[cpu]
+            .current_tid
+            .is_none_or(|v| self.threads[&v].priority < priority)
+        {
+            smp::kick(cpu);
+        }
+    }
+
+    pub fn set_priority(&mut self, tid: u64, priority: u8) {
+        let thread = self.threads.get_mut(&tid).unwrap();
+        let old = core::mem::replace(&mut thread.priority, priority);
+        if thread.state.is_inactive() {
+            let queues = &mut self.run_queues[thread.cpu];
+            queues[old as usize].retain(|&v| v != tid);
+            queues[priority as usize].push_back(tid);
+        }
+    }
+
+    fn top_ready_priority(&self) -> Option<u8> {
+        self.run_queues[smp::current().id]
+            .iter()
+            .rposition(|v| !v.is_empty())
+            .map(|v| v as u8)
+    }
+
+    /// Whether the current thread used up its time slice, died elsewhere or is outranked by a ready
+    /// thread. An idle CPU also goes looking for threads queued on other CPUs.
+    pub fn should_preempt(&self) -> bool {
+        let Some(current) = self.current_thread() else {
+            return self.run_queues.iter().flatten().any(|v| !v.is_empty());
+        };
+        current.state.is_dying()
+            || current.slice_end <= crate::timer::monotonic_ns()
+            || self
+                .top_ready_priority()
+                .is_some_and(|v| v > current.priority)
+    }
+
+    fn tick(&mut self) {
+        // Sleeps and timers are kept by the BSP alone.
+        if smp::current().id != 0 {
+            return;
+        }
+
+        let now = crate::timer::monotonic_ns();
+
+        let mut woken = vec![];
+        for thread in self.threads.values_mut() {
+            if !thread.state.is_suspended() {
+                continue;
+            }
+            if thread
+                .msg_wait
+                .is_some_and(|v| v.deadline.is_some_and(|v| v <= now))
+            {
+                thread.msg_wait = None;
+                super::userland::handlers::msg::deliver(&mut thread.regs, None);
+                woken.push(thread.id);
+            } else if thread.sleep_until.is_some_and(|v| v <= now) {
+                thread.sleep_until = None;
+                woken.push(thread.id);
+            }
+        }
+        for tid in woken {
+            self.make_ready(tid);
+        }
+
+        let fired: Vec<_> = self
+            .timers
+            .iter_mut()
+            .filter(|(_, v)| v.deadline <= now)
+            .map(|(&id, v)| {
+                if let Some(period) = v.period {
+                    // Skip the periods that were missed rather than firing in a burst.
+                    v.deadline = (v.deadline + period).max(now + period);
+                }
+                (id, v.owner, v.period.is_none())
+            })
+            .collect();
+        for (id, pid, oneshot) in fired {
+            if oneshot {
+                self.timers.remove(&id);
+                self.timer_id_gen.free(id);
+            }
+            let _ = self.send_kernel_msg(pid, &KernelMessage::TimerFired(id));
+        }
+    }
+
+    /// Earliest time the calling CPU has to look at the scheduler again: the end of the current
+    /// time slice, or on the BSP also the next sleep, message wait or timer to expire.
+    fn next_deadline(&self) -> Option<u64> {
+        let slice_end = self.current_thread().map(|v| v.slice_end);
+        if smp::current().id != 0 {
+            return slice_end;
+        }
+
+        let waits = self
+            .threads
+            .values()
+            .filter(|v| v.state.is_suspended())
+            .filter_map(|v| {
+                let msg_deadline = v.msg_wait.and_then(|v| v.deadline);
+                match (msg_deadline, v.sleep_until) {
+                    (Some(a), Some(b)) => Some(a.min(b)),
+                    (a, b) => a.or(b),
+                }
+            });
+        let timers = self.timers.values().map(|v| v.deadline);
+        slice_end.into_iter().chain(waits).chain(timers).min()
+    }
+
+    /// Programs the timer of the calling CPU for [`Self::next_deadline`], or stops it if there is
+    /// nothing to wait for.
+    pub fn arm_timer(&self) {
+        let lapic = unsafe { &*crate::system::state::SYS_STATE.get() }
+            .lapic
+            .as_ref()
+            .unwrap();
+        match self.next_deadline() {
+            Some(v) => lapic.arm_timer(v.saturating_sub(crate::timer::monotonic_ns())),
+            None => lapic.disarm_timer(),
+        }
+    }
+
+    /// Gets the BSP to account for a sleep, message wait or timer that was just added.
+    pub fn deadline_added(&self) {
+        if smp::current().id == 0 {
+            self.arm_timer();
+        } else {
+            smp::poke_timer(0);
+        }
+    }
+
+    pub fn current_tid(&self) -> Option<u64> {
+        smp::current().current_tid
+    }
+
+    pub fn current_pid(&self) -> Option<u64> {
+        smp::current().current_pid
+    }
+
+    pub fn current_thread(&self) -> Option<&super::Thread> {
+        self.threads.get(&self.current_tid()?)
     }
 
     pub fn current_thread_mut(&mut self) -> Option<&mut super::Thread> {
-        self.threads.get_mut(&self.current_tid?)
+        self.threads.get_mut(&self.current_tid()?)
     }
 
     pub fn current_process(&self) -> Option<&super::Process> {
-        self.processes.get(&self.current_pid?)
+        self.processes.get(&self.current_pid()?)
     }
 
     pub fn current_process_mut(&mut self) -> Option<&mut super::Process> {
-        self.processes.get_mut(&self.current_pid?)
+        self.processes.get_mut(&self.current_pid()?)
     }
 
+    /// Takes the first ready thread of the highest priority off the queues of this CPU, or steals
+    /// the highest priority one of any other CPU if there is none.
     pub fn next_thread_mut(&mut self) -> Option<&mut super::Thread> {
-        let mut i = self.current_tid.map(|v| v + 1).unwrap_or_default() as usize;
-        if i > self.threads.len() {
-            i = 0;
-        }
-
-        self.threads
-            .values_mut()
-            .skip(i)
-            .find(|v| v.state.is_inactive())
+        let cpu = smp::current().id;
+        let tid = self.run_queues[cpu]
+            .iter_mut()
+            .rev()
+            .find_map(VecDeque::pop_front)
+            .or_else(|| {
+                (0..skykit::priority::LEVELS as usize)
+                    .rev()
+                    .find_map(|prio| self.run_queues.iter_mut().find_map(|v| v[prio].pop_front()))
+            })?;
+        let thread = self.threads.get_mut(&tid)?;
+        thread.cpu = cpu;
+        Some(thread)
     }
 
     pub unsafe fn schedule(&mut self, state: &mut RegisterState) {
+        let cpu = smp::current();
         if let Some(old_thread) = self.current_thread_mut() {
-            old_thread.regs = *state;
-            if !old_thread.state.is_suspended() {
-                old_thread.state = super::ThreadState::Inactive;
+            if old_thread.state.is_dying() {
+                let tid = old_thread.id;
+                self.reap(tid);
+            } else {
+                old_thread.regs = *state;
+                old_thread.ext_state.save();
+                old_thread.fs_base = FsBase::read().base() as _;
+                // The user `GS` base sits in the swap register while in the kernel.
+                old_thread.gs_base = KernelGsBase::read().base() as _;
+                if !old_thread.state.is_suspended() {
+                    let tid = old_thread.id;
+                    self.make_ready(tid);
+                }
             }
         }
 
@@ -233,7 +460,7 @@ impl Scheduler {
                 rip: idle as usize as _,
                 cs: SegmentSelector::new(1, PrivilegeLevel::Supervisor).into(),
                 rflags: 0x202,
-                rsp: self.kern_stack.as_ptr() as u64 + self.kern_stack.len() as u64,
+                rsp: cpu.kern_rsp,
                 ss: SegmentSelector::new(2, PrivilegeLevel::Supervisor).into(),
                 ..Default::default()
             };
@@ -243,31 +470,112 @@ impl Scheduler {
                 .unwrap()
                 .lock()
                 .set_cr3();
-            self.current_tid = None;
-            self.current_pid = None;
+            cpu.current_tid = None;
+            cpu.current_pid = None;
+            self.arm_timer();
             return;
         };
 
         *state = thread.regs;
+        thread.ext_state.restore();
+        FsBase::new().with_base(thread.fs_base as _).write();
+        KernelGsBase::new().with_base(thread.gs_base as _).write();
         thread.state = super::ThreadState::Active;
+        let now = crate::timer::monotonic_ns();
+        if thread.slice_end <= now {
+            thread.slice_end = now + super::QUANTUM_NS;
+        }
         let pid = thread.pid;
         let tid = Some(thread.id);
         self.processes.get_mut(&pid).unwrap().cr3.lock().set_cr3();
-        self.current_tid = tid;
-        self.current_pid = Some(pid);
+        cpu.current_tid = tid;
+        cpu.current_pid = Some(pid);
+        self.load_io_bitmap(pid);
+        self.arm_timer();
+    }
+
+    /// Drops a thread of a torn down process once its CPU left it, and the process with its last
+    /// thread.
+    unsafe fn reap(&mut self, tid: u64) {
+        let thread = self.threads.remove(&tid).unwrap();
+        self.tid_gen.free(tid);
+        smp::current().current_tid = None;
+        smp::current().current_pid = None;
+
+        let pid = thread.pid;
+        let proc = self.dying.get(&pid).unwrap();
+        if proc.thread_ids.iter().any(|v| self.threads.contains_key(v)) {
+            return;
+        }
+        // Its page tables are still loaded.
+        (*crate::system::state::SYS_STATE.get())
+            .pml4
+            .as_ref()
+            .unwrap()
+            .lock()
+            .set_cr3();
+        self.dying.remove(&pid);
+        for (id, shm) in self.dying_shm.remove(&pid).into_iter().flatten() {
+            self.destroy_shared_mem(id, &shm);
+        }
+        self.free_pid(pid);
+    }
+
+    /// Swaps in the I/O permission bitmap of `pid`, skipping the copy when it is already loaded.
+    pub fn load_io_bitmap(&mut self, pid: u64) {
+        let cpu = smp::current();
+        if cpu.io_bitmap_owner == Some(pid) {
+            return;
+        }
+        let bitmap = self.processes.get(&pid).unwrap().io_bitmap.as_deref();
+        if bitmap.is_none() && cpu.io_bitmap_owner.is_none() {
+            return;
+        }
+        cpu.tss.set_io_bitmap(bitmap);
+        cpu.io_bitmap_owner = bitmap.map(|_| pid);
+    }
+
+    /// Refreshes the I/O permission bitmap of `pid` on every CPU that has it loaded.
+    pub fn reload_io_bitmap(&mut self, pid: u64) {
+        let bitmap = self.processes.get(&pid).unwrap().io_bitmap.as_deref();
+        let state = unsafe { &mut *crate::system::state::SYS_STATE.get() };
+        for cpu in state
+            .cpus
+            .iter_mut()
+            .filter(|v| v.io_bitmap_owner == Some(pid))
+        {
+            cpu.tss.set_io_bitmap(bitmap);
+        }
+        self.load_io_bitmap(pid);
+    }
+
+    fn free_pid(&mut self, pid: u64) {
+        let state = unsafe { &mut *crate::system::state::SYS_STATE.get() };
+        for cpu in state
+            .cpus
+            .iter_mut()
+            .filter(|v| v.io_bitmap_owner == Some(pid))
+        {
+            cpu.tss.set_io_bitmap(None);
+            cpu.io_bitmap_owner = None;
+        }
+        self.pid_gen.free(pid);
     }
 
     pub fn register_irq(
         &mut self,
-        state: &RegisterState,
+        state: &mut RegisterState,
     ) -> ControlFlow<Option<TerminationReason>> {
         let irq = state.rsi as u8;
         if irq > 0xDF {
-            return ControlFlow::Break(Some(TerminationReason::MalformedArgument));
+            return fail(state, SkyError::InvalidArgument);
+        }
+        if !self.current_process().unwrap().caps.allows_irq(irq) {
+            return ControlFlow::Break(Some(TerminationReason::InsufficientPermissions));
         }
-        let pid = self.current_pid.unwrap();
+        let pid = self.current_pid().unwrap();
         if self.irq_handlers.try_insert(irq, pid).is_err() {
-            return ControlFlow::Break(Some(TerminationReason::AlreadyExists));
+            return fail(state, SkyError::AlreadyExists);
         }
 
         crate::acpi::ioapic::wire_legacy_irq(irq, false);
@@ -283,31 +591,207 @@ impl Scheduler {
         ControlFlow::Continue(())
     }
 
-    pub fn thread_teardown(&mut self) -> ControlFlow<Option<TerminationReason>> {
-        let id = self.current_tid.take().unwrap();
-        self.threads.remove(&id);
-        self.tid_gen.free(id);
+    pub fn send_kernel_msg(
+        &mut self,
+        pid: u64,
+        msg: &KernelMessage,
+    ) -> ControlFlow<Option<TerminationReason>> {
+        let data = postcard::to_allocvec(msg).unwrap();
+        let s = crate::system::allocator::alloc_pages(data.len());
+        s.copy_from_slice(&data);
+
+        let process = self.processes.get_mut(&pid).unwrap();
+        let Some(virt) = process.track_kernelside_alloc(s.as_ptr() as _, s.len() as _) else {
+            warn!("PID {pid}: No room for kernel message");
+            unsafe { crate::system::allocator::free_pages(s) }
+            return ControlFlow::Continue(());
+        };
+        let msg = Message::new(self.msg_id_gen.next(), 0, unsafe {
+            core::slice::from_raw_parts(virt as *const _, s.len() as _)
+        });
+        self.message_sources.insert(msg.id, (0, pid));
+        let process = self.processes.get_mut(&pid).unwrap();
+        process.track_msg(msg.id, virt);
+
+        let tids = process.thread_ids.clone();
+        super::userland::handlers::msg::handle_new(self, pid, tids, msg)
+    }
+
+    pub fn notify_service_watchers(&mut self, name: &str, msg: &KernelMessage) {
+        let Some(watchers) = self.service_watchers.get(name).cloned() else {
+            return;
+        };
+        for pid in watchers {
+            if self.processes.contains_key(&pid) {
+                let _ = self.send_kernel_msg(pid, msg);
+            }
+        }
+    }
+
+    fn release_services(&mut self, pid: u64) {
+        for watchers in self.service_watchers.values_mut() {
+            watchers.remove(&pid);
+        }
+        self.service_watchers.retain(|_, v| !v.is_empty());
+
+        let names: Vec<_> = self
+            .services
+            .extract_if(|_, v| *v == pid)
+            .map(|(k, _)| k)
+            .collect();
+        for name in names {
+            trace!("PID {pid}: Dropping service {name}");
+            self.notify_service_watchers(&name, &KernelMessage::ServiceUnregistered(name.clone()));
+        }
+    }
 
-        let proc = self.current_process_mut().unwrap();
+    /// `defer` keeps the memory owned by `pid` around until the process is reaped, for when its
+    /// threads are still running on other CPUs.
+    fn release_shared_mem(&mut self, pid: u64, defer: bool) {
+        for shm in self.shared_mem.values_mut() {
+            shm.grants.remove(&pid);
+        }
+
+        let owned: Vec<_> = self.shared_mem.extract_if(|_, v| v.owner == pid).collect();
+        for (id, shm) in owned {
+            trace!("PID {pid}: Destroying shared memory {id}");
+            for grantee in shm.grants.keys() {
+                let Some(process) = self.processes.get_mut(grantee) else {
+                    continue;
+                };
+                if let Some(addr) = process.shm_mapping(id) {
+                    process.free_alloc(addr);
+                }
+            }
+            if defer {
+                self.dying_shm.entry(pid).or_default().push((id, shm));
+            } else {
+                self.destroy_shared_mem(id, &shm);
+            }
+        }
+    }
+
+    fn destroy_shared_mem(&mut self, id: u64, shm: &super::SharedMemory) {
+        unsafe {
+            (*crate::system::state::SYS_STATE.get())
+                .pmm
+                .as_ref()
+                .unwrap()
+                .lock()
+                .free(shm.phys as *mut _, shm.size.div_ceil(0x1000));
+        }
+        self.shm_id_gen.free(id);
+    }
+
+    fn release_timers(&mut self, pid: u64) {
+        let owned: Vec<_> = self
+            .timers
+            .extract_if(|_, v| v.owner == pid)
+            .map(|(k, _)| k)
+            .collect();
+        for id in owned {
+            self.timer_id_gen.free(id);
+        }
+    }
+
+    fn release_process_watchers(&mut self, pid: u64, code: u64, reason: Option<TerminationReason>) {
+        for watchers in self.process_watchers.values_mut() {
+            watchers.remove(&pid);
+        }
+        self.process_watchers.retain(|_, v| !v.is_empty());
+
+        let msg = KernelMessage::ProcessExited { pid, code, reason };
+        for watcher in self.process_watchers.remove(&pid).unwrap_or_default() {
+            if self.processes.contains_key(&watcher) {
+                let _ = self.send_kernel_msg(watcher, &msg);
+            }
+        }
+    }
+
+    pub fn thread_teardown(&mut self, value: u64) -> ControlFlow<Option<TerminationReason>> {
+        let id = smp::current().current_tid.take().unwrap();
+        let thread = self.threads.remove(&id).unwrap();
+
+        let proc = self.processes.get_mut(&thread.pid).unwrap();
         proc.thread_ids.remove(&id);
         if proc.thread_ids.is_empty() {
-            let pid = self.current_pid.take().unwrap();
-            self.processes.remove(&pid);
-            self.pid_gen.free(pid);
+            self.tid_gen.free(id);
+            self.process_teardown(value, None);
+            return ControlFlow::Break(None);
+        }
+        proc.free_alloc(thread.stack_addr);
+        if let Some(addr) = thread.tls_addr {
+            proc.free_alloc(addr);
+        }
+
+        let mut joiners = vec![];
+        for joiner in self
+            .threads
+            .values_mut()
+            .filter(|v| v.join_wait == Some(id))
+        {
+            joiner.join_wait = None;
+            joiner.regs.rax = value;
+            joiners.push(joiner.id);
+        }
+        if proc.detached_threads.remove(&id) || !joiners.is_empty() {
+            self.tid_gen.free(id);
+        } else {
+            proc.exited_threads.insert(id, value);
+        }
+        for tid in joiners {
+            self.make_ready(tid);
         }
 
         ControlFlow::Break(None)
     }
 
-    pub fn process_teardown(&mut self) {
+    /// `reason` is set when the process was killed rather than exiting by itself.
+    pub fn process_teardown(&mut self, code: u64, reason: Option<TerminationReason>) {
         // TODO: Teardown any residual messages too.
-        self.current_tid = None;
-        let pid = self.current_pid.take().unwrap();
+        let cpu = smp::current();
+        cpu.current_tid = None;
+        let pid = cpu.current_pid.take().unwrap();
         let proc = self.processes.remove(&pid).unwrap();
-        for tid in &proc.thread_ids {
+        // Threads running on other CPUs can only go once those CPUs switch away from them.
+        let mut remote = vec![];
+        for tid in proc.thread_ids.iter().chain(proc.exited_threads.keys()) {
+            if let Some(thread) = self.threads.get_mut(tid) {
+                if thread.state.is_active() && thread.cpu != cpu.id {
+                    thread.state = super::ThreadState::Dying;
+                    remote.push(thread.cpu);
+                    continue;
+                }
+            }
             self.threads.remove(tid);
             self.tid_gen.free(*tid);
         }
-        self.pid_gen.free(pid);
+        for queue in self.run_queues.iter_mut().flatten() {
+            queue.retain(|v| !proc.thread_ids.contains(v));
+        }
+        self.release_services(pid);
+        self.release_shared_mem(pid, !remote.is_empty());
+        self.release_timers(pid);
+        self.release_process_watchers(pid, code, reason);
+        crate::system::fkext::mark_exited(pid, code);
+
+        // The page tables of the process go with it, so stop using them first.
+        unsafe {
+            (*crate::system::state::SYS_STATE.get())
+                .pml4
+                .as_ref()
+                .unwrap()
+                .lock()
+                .set_cr3();
+        }
+        if remote.is_empty() {
+            drop(proc);
+            self.free_pid(pid);
+            return;
+        }
+        self.dying.insert(pid, proc);
+        for id in remote {
+            smp::kick(id);
+        }
     }
 }
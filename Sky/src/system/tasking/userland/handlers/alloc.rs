@@ -2,33 +2,75 @@
 
 use core::ops::ControlFlow;
 
-use skykit::TerminationReason;
+use amd64::paging::buddy;
+use skykit::{SkyError, TerminationReason};
 
+use super::fail;
 use crate::system::{tasking::scheduler::Scheduler, RegisterState};
 
 pub fn alloc(
     scheduler: &mut Scheduler,
     state: &mut RegisterState,
 ) -> ControlFlow<Option<TerminationReason>> {
+    let size = state.rsi;
+    if size == 0 || size.checked_next_multiple_of(0x1000).is_none() {
+        return fail(state, SkyError::InvalidArgument);
+    }
+
     let process = scheduler.current_process_mut().unwrap();
-    let (addr, pages) = process.allocate(state.rsi);
+    let Some(addr) = process.allocate_lazy(size) else {
+        return fail(state, SkyError::OutOfMemory);
+    };
+
+    state.rax = addr;
+    ControlFlow::Continue(())
+}
+
+pub fn dma_alloc(
+    scheduler: &mut Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let (size, align, below_4g) = (state.rsi, state.rdx.max(0x1000), state.rcx != 0);
+    // The PMM has no blocks aligned to more than the largest block size.
+    if size == 0
+        || size.checked_next_multiple_of(0x1000).is_none()
+        || !align.is_power_of_two()
+        || align / 0x1000 > 1 << buddy::MAX_ORDER
+    {
+        return fail(state, SkyError::InvalidArgument);
+    }
+
+    let max_addr = if below_4g { 0x1_0000_0000 } else { u64::MAX };
+    let process = scheduler.current_process_mut().unwrap();
+    let Some((addr, phys)) = process.allocate_dma(size, align, max_addr) else {
+        return fail(state, SkyError::OutOfMemory);
+    };
 
     unsafe {
-        core::ptr::write_bytes(addr as *mut u8, 0, (pages * 0x1000) as _);
+        core::ptr::write_bytes(addr as *mut u8, 0, size.next_multiple_of(0x1000) as _);
     }
 
     state.rax = addr;
+    state.rdx = phys;
     ControlFlow::Continue(())
 }
 
 pub fn free(
     scheduler: &mut Scheduler,
-    state: &RegisterState,
+    state: &mut RegisterState,
 ) -> ControlFlow<Option<TerminationReason>> {
     let addr = state.rsi;
 
     let process = scheduler.current_process_mut().unwrap();
-    if process.is_msg(addr) {
+    if !process.allocations.contains_key(&addr) {
+        return fail(state, SkyError::NotFound);
+    }
+    if process
+        .allocations
+        .get(&addr)
+        .is_some_and(|(_, ty)| ty.is_shared() || ty.is_mmio())
+        || process.is_msg(addr)
+    {
         return ControlFlow::Break(Some(TerminationReason::MalformedAddress));
     }
 
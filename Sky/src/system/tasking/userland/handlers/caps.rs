@@ -0,0 +1,50 @@
+// Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use core::ops::ControlFlow;
+
+use skykit::{caps::SKCapabilities, SkyError, TerminationReason};
+
+use super::{fail, os_dt_entry::is_within};
+use crate::system::{tasking::scheduler::Scheduler, RegisterState};
+
+pub fn grant(
+    scheduler: &mut Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let (target, addr, size) = (state.rsi, state.rdx, state.rcx);
+    let pid = scheduler.current_pid().unwrap();
+
+    let process = scheduler.current_process_mut().unwrap();
+    if !process.fault_in(addr, size) {
+        return ControlFlow::Break(Some(TerminationReason::MalformedAddress));
+    }
+    let data = unsafe { core::slice::from_raw_parts(addr as *const _, size as _) };
+    let Ok(caps) = postcard::from_bytes::<SKCapabilities>(data) else {
+        return ControlFlow::Break(Some(TerminationReason::MalformedBody));
+    };
+
+    let held = {
+        let sys_state = unsafe { &*crate::system::state::SYS_STATE.get() };
+        let dt_index = sys_state.dt_index.as_ref().unwrap().read();
+        process.caps.covers(&caps)
+            && caps
+                .osdt_entries
+                .iter()
+                .all(|&v| is_within(&dt_index, &process.caps.osdt_entries, v))
+    };
+    if !held {
+        return ControlFlow::Break(Some(TerminationReason::InsufficientPermissions));
+    }
+
+    let Some(child) = scheduler.processes.get_mut(&target) else {
+        return fail(state, SkyError::NotFound);
+    };
+    if child.parent != Some(pid) {
+        return fail(state, SkyError::InsufficientPermissions);
+    }
+
+    trace!("PID {pid}: Granting {caps:?} to PID {target}");
+    child.caps.extend(caps);
+
+    ControlFlow::Continue(())
+}
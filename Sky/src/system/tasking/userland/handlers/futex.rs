@@ -0,0 +1,68 @@
+// Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use alloc::vec::Vec;
+use core::{
+    ops::ControlFlow,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use skykit::TerminationReason;
+
+use crate::system::{
+    tasking::{scheduler::Scheduler, ThreadState},
+    RegisterState,
+};
+
+/// Returns the physical address of the futex word, its key, which is the same in every process
+/// mapping it through shared memory.
+fn check_addr(scheduler: &mut Scheduler, addr: u64) -> ControlFlow<Option<TerminationReason>, u64> {
+    let process = scheduler.current_process_mut().unwrap();
+    if addr % 4 != 0 || !process.fault_in(addr, 4) {
+        return ControlFlow::Break(Some(TerminationReason::MalformedAddress));
+    }
+    ControlFlow::Continue(process.kern_addr(addr) - amd64::paging::PHYS_VIRT_OFFSET)
+}
+
+pub fn wait(
+    scheduler: &mut Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let (addr, expected) = (state.rsi, state.rdx as u32);
+    let key = check_addr(scheduler, addr)?;
+
+    // The scheduler lock keeps a wake from slipping in between the check and the suspension.
+    let word = scheduler.current_process().unwrap().kern_addr(addr) as *const AtomicU32;
+    if unsafe { &*word }.load(Ordering::SeqCst) != expected {
+        return ControlFlow::Continue(());
+    }
+
+    let thread = scheduler.current_thread_mut().unwrap();
+    thread.state = ThreadState::Suspended;
+    thread.futex_wait = Some(key);
+    ControlFlow::Break(None)
+}
+
+pub fn wake(
+    scheduler: &mut Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let (addr, count) = (state.rsi, state.rdx);
+    let key = check_addr(scheduler, addr)?;
+
+    let woken: Vec<_> = scheduler
+        .threads
+        .values_mut()
+        .filter(|v| v.futex_wait == Some(key))
+        .take(count as _)
+        .map(|v| {
+            v.futex_wait = None;
+            v.id
+        })
+        .collect();
+
+    state.rax = woken.len() as _;
+    for tid in woken {
+        scheduler.make_ready(tid);
+    }
+    ControlFlow::Continue(())
+}
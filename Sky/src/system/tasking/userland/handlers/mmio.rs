@@ -0,0 +1,66 @@
+// Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use core::ops::ControlFlow;
+
+use skykit::{syscall::MmioCaching, SkyError, TerminationReason};
+
+use super::fail;
+use crate::system::{
+    tasking::{scheduler::Scheduler, vma::Area, AllocationType},
+    RegisterState,
+};
+
+pub fn map(
+    scheduler: &mut Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let (phys, size) = (state.rsi, state.rdx);
+    let Ok(caching) = MmioCaching::try_from(state.rcx) else {
+        return ControlFlow::Break(Some(TerminationReason::MalformedArgument));
+    };
+    if size == 0 {
+        return fail(state, SkyError::InvalidArgument);
+    }
+
+    let process = scheduler.current_process_mut().unwrap();
+    if !process.caps.allows_mmio(phys, size) {
+        return fail(state, SkyError::InsufficientPermissions);
+    }
+
+    // Registers rarely start on a page boundary, e.g. small PCI BARs.
+    let base = phys & !0xFFF;
+    trace!(
+        "PID {}: Mapping MMIO {phys:#X} ({size} bytes, {caching:?})",
+        process.id
+    );
+    let Some(virt) = process.track_alloc(
+        Area::Heap,
+        base,
+        phys - base + size,
+        AllocationType::Mmio(caching),
+    ) else {
+        return fail(state, SkyError::OutOfMemory);
+    };
+
+    state.rax = virt + (phys - base);
+    ControlFlow::Continue(())
+}
+
+pub fn unmap(
+    scheduler: &mut Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let virt = state.rsi & !0xFFF;
+
+    let process = scheduler.current_process_mut().unwrap();
+    if !process
+        .allocations
+        .get(&virt)
+        .is_some_and(|(_, ty)| ty.is_mmio())
+    {
+        return fail(state, SkyError::NotFound);
+    }
+    process.free_alloc(virt);
+
+    ControlFlow::Continue(())
+}
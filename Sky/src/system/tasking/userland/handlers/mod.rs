@@ -2,26 +2,40 @@
 
 use core::{fmt::Write, ops::ControlFlow};
 
-use skykit::TerminationReason;
+use skykit::{SkyError, TerminationReason};
 
 use crate::system::{tasking::scheduler::Scheduler, RegisterState};
 
 pub mod alloc;
+pub mod caps;
+pub mod futex;
+pub mod mmio;
 pub mod msg;
 pub mod os_dt_entry;
 pub mod port;
+pub mod process;
+pub mod service;
+pub mod shm;
+pub mod thread;
+pub mod time;
+
+/// Fails the syscall with `err` without punishing the caller.
+pub fn fail(state: &mut RegisterState, err: SkyError) -> ControlFlow<Option<TerminationReason>> {
+    state.r9 = err.into();
+    ControlFlow::Continue(())
+}
 
 pub fn kprint(
-    scheduler: &Scheduler,
+    scheduler: &mut Scheduler,
     state: &RegisterState,
 ) -> ControlFlow<Option<TerminationReason>> {
     let addr = state.rsi;
     let size = state.rdx;
 
     if !scheduler
-        .current_process()
+        .current_process_mut()
         .unwrap()
-        .region_is_valid(addr, size)
+        .fault_in(addr, size)
     {
         return ControlFlow::Break(Some(TerminationReason::MalformedAddress));
     }
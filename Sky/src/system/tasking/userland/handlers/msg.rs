@@ -2,36 +2,42 @@
 
 use core::ops::ControlFlow;
 
-use amd64::paging::{PageTableFlags, PAGE_SIZE};
 use hashbrown::HashSet;
 use skykit::{
     msg::{KernelMessage, Message},
-    TerminationReason,
+    SkyError, TerminationReason,
 };
 
+use super::fail;
 use crate::system::{
-    tasking::{scheduler::Scheduler, ThreadState},
+    tasking::{scheduler::Scheduler, MessageWait, ThreadState},
     RegisterState,
 };
 
+#[inline]
+pub fn deliver(state: &mut RegisterState, msg: Option<&Message>) {
+    state.rax = msg.map_or(0, |v| v.id);
+    state.rdi = msg.map_or(0, |v| v.pid);
+    state.rsi = msg.map_or(0, |v| v.data.as_ptr() as u64);
+    state.rdx = msg.map_or(0, |v| v.data.len() as u64);
+}
+
 pub fn handle_new(
     scheduler: &mut Scheduler,
     pid: u64,
     tids: HashSet<u64>,
     msg: Message,
 ) -> ControlFlow<Option<TerminationReason>> {
-    let idle = scheduler.current_tid.is_none();
     for tid in tids {
         let thread = scheduler.threads.get_mut(&tid).unwrap();
-        if !thread.state.is_suspended() {
+        if !thread.state.is_suspended() || !thread.msg_wait.is_some_and(|v| v.accepts(msg.pid)) {
             continue;
         }
-        thread.state = ThreadState::Inactive;
-        thread.regs.rax = msg.id;
-        thread.regs.rdi = msg.pid;
-        thread.regs.rsi = msg.data.as_ptr() as _;
-        thread.regs.rdx = msg.data.len() as _;
-        if idle {
+        thread.msg_wait = None;
+        deliver(&mut thread.regs, Some(&msg));
+        scheduler.make_ready(tid);
+        // Let a higher priority receiver, such as an IRQ handler, run right away.
+        if scheduler.should_preempt() {
             return ControlFlow::Break(None);
         }
         return ControlFlow::Continue(());
@@ -43,94 +49,147 @@ pub fn handle_new(
 
 pub fn send(
     scheduler: &mut Scheduler,
-    state: &RegisterState,
+    state: &mut RegisterState,
 ) -> ControlFlow<Option<TerminationReason>> {
-    let src = scheduler.current_pid.unwrap();
+    let src = scheduler.current_pid().unwrap();
     let target = state.rsi;
-    if src == target {
-        return ControlFlow::Break(Some(TerminationReason::MalformedArgument));
-    }
 
     let (addr, size) = (state.rdx, state.rcx);
-    if !scheduler
-        .current_process()
-        .unwrap()
-        .region_is_within_bounds(addr, size)
+    let process = scheduler.current_process_mut().unwrap();
+    if !process.region_is_within_bounds(addr, size)
+        || process.allocations.get(&addr).unwrap().1.is_shared()
+        || process.is_msg(addr)
     {
         return ControlFlow::Break(Some(TerminationReason::MalformedAddress));
     }
 
+    // The buffer is handed over to the kernel even if the message can't be delivered.
+    // Its pages are shared with the receiver, so all of them need backing first.
+    let alloc_size = process.allocations[&addr].0;
+    if !process.fault_in(addr, alloc_size) {
+        process.free_alloc(addr);
+        return fail(state, SkyError::OutOfMemory);
+    }
+    if src == target {
+        process.free_alloc(addr);
+        return fail(state, SkyError::InvalidArgument);
+    }
     if !scheduler.processes.contains_key(&target) {
-        return ControlFlow::Break(Some(TerminationReason::NotFound));
+        scheduler.current_process_mut().unwrap().free_alloc(addr);
+        return fail(state, SkyError::NotFound);
     }
 
+    // The receiver gets the same pages at an address of its own.
+    let frames = scheduler.current_process().unwrap().frames_of(addr);
+    let process = scheduler.processes.get_mut(&target).unwrap();
+    let Some(virt) = process.map_foreign(&frames) else {
+        scheduler.current_process_mut().unwrap().free_alloc(addr);
+        return fail(state, SkyError::OutOfMemory);
+    };
     let msg = Message::new(scheduler.msg_id_gen.next(), src, unsafe {
-        core::slice::from_raw_parts(addr as *const _, size as _)
+        core::slice::from_raw_parts(virt as *const _, size as _)
     });
-    scheduler.message_sources.insert(msg.id, src);
+    process.msg_mappings.insert(msg.id, virt);
+    let tids = process.thread_ids.clone();
+    scheduler.message_sources.insert(msg.id, (src, target));
 
     let cur = scheduler.current_process_mut().unwrap();
 
     cur.track_msg(msg.id, addr);
 
-    let process = scheduler.processes.get(&target).unwrap();
-    unsafe {
-        process.cr3.lock().map(
-            addr,
-            addr - skykit::USER_VIRT_OFFSET,
-            size.div_ceil(PAGE_SIZE),
-            PageTableFlags::new_present().with_user(true),
-        );
-    }
-    let tids = process.thread_ids.clone();
     handle_new(scheduler, target, tids, msg)
 }
 
-pub fn recv(
+fn recv_inner(
     scheduler: &mut Scheduler,
     state: &mut RegisterState,
+    wait: MessageWait,
 ) -> ControlFlow<Option<TerminationReason>> {
     let process = scheduler.current_process_mut().unwrap();
-    let Some(msg) = process.messages.pop_back() else {
-        scheduler.current_thread_mut().unwrap().state = ThreadState::Suspended;
-        return ControlFlow::Break(None);
+    let msg = process
+        .messages
+        .iter()
+        .rposition(|v| wait.accepts(v.pid))
+        .and_then(|i| process.messages.remove(i));
+    let now = crate::timer::monotonic_ns();
+    if msg.is_some() || wait.deadline.is_some_and(|v| v <= now) {
+        deliver(state, msg.as_ref());
+        return ControlFlow::Continue(());
+    }
+
+    let thread = scheduler.current_thread_mut().unwrap();
+    thread.state = ThreadState::Suspended;
+    thread.msg_wait = Some(wait);
+    if wait.deadline.is_some() {
+        scheduler.deadline_added();
+    }
+    ControlFlow::Break(None)
+}
+
+pub fn recv(
+    scheduler: &mut Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let wait = MessageWait {
+        from: None,
+        deadline: None,
     };
+    recv_inner(scheduler, state, wait)
+}
 
-    state.rax = msg.id;
-    state.rdi = msg.pid;
-    state.rsi = msg.data.as_ptr() as u64;
-    state.rdx = msg.data.len() as u64;
-    ControlFlow::Continue(())
+pub fn recv_filtered(
+    scheduler: &mut Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let wait = MessageWait {
+        from: (state.rsi != u64::MAX).then_some(state.rsi),
+        deadline: (state.rdx != u64::MAX).then(|| {
+            crate::timer::monotonic_ns().saturating_add(state.rdx.saturating_mul(1_000_000))
+        }),
+    };
+    recv_inner(scheduler, state, wait)
 }
 
 pub fn ack(
     scheduler: &mut Scheduler,
-    state: &RegisterState,
+    state: &mut RegisterState,
 ) -> ControlFlow<Option<TerminationReason>> {
     let msg_id = state.rsi;
 
-    let Some(src_pid) = scheduler.message_sources.remove(&msg_id) else {
-        return ControlFlow::Break(Some(TerminationReason::NotFound));
+    // Only the receiver may acknowledge a message.
+    let cur_pid = scheduler.current_pid().unwrap();
+    let Some(&(src_pid, _)) = scheduler
+        .message_sources
+        .get(&msg_id)
+        .filter(|(_, dst)| *dst == cur_pid)
+    else {
+        return fail(state, SkyError::NotFound);
     };
 
-    let cur_pid = scheduler.current_pid.unwrap();
     let pid = if src_pid == 0 { cur_pid } else { src_pid };
-    let process = scheduler.processes.get_mut(&pid).unwrap();
-    let addr = *process.msg_id_to_addr.get(&msg_id).unwrap();
-    let size = process.allocations.get(&addr).copied().unwrap().0;
+    let Some(process) = scheduler.processes.get_mut(&pid) else {
+        return fail(state, SkyError::NotFound);
+    };
+    let Some(&addr) = process.msg_id_to_addr.get(&msg_id) else {
+        return fail(state, SkyError::NotFound);
+    };
+    let size = process.allocations[&addr].0;
+    scheduler.message_sources.remove(&msg_id);
+
     if src_pid == 0 {
         let msg: KernelMessage = unsafe {
             postcard::from_bytes(core::slice::from_raw_parts(addr as *const _, size as _)).unwrap()
         };
-        let KernelMessage::IRQFired(irq) = msg;
-        crate::acpi::ioapic::set_irq_mask(irq, false);
+        if let KernelMessage::IRQFired(irq) = msg {
+            crate::acpi::ioapic::set_irq_mask(irq, false);
+        }
     }
     process.free_msg(msg_id);
     scheduler.msg_id_gen.free(msg_id);
     if pid != cur_pid {
-        let process = scheduler.current_process().unwrap();
-        unsafe {
-            process.cr3.lock().unmap(addr, (size + 0xFFF) / 0x1000);
+        let process = scheduler.current_process_mut().unwrap();
+        if let Some(addr) = process.msg_mappings.remove(&msg_id) {
+            process.unmap_foreign(addr);
         }
     }
 
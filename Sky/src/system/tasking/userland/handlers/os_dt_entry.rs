@@ -2,21 +2,47 @@
 
 use core::ops::ControlFlow;
 
+use hashbrown::HashMap;
 use skykit::{
-    osdtentry::{OSDTEntryInfo, OSDTEntryProp},
-    TerminationReason,
+    osdtentry::{OSDTEntryInfo, OSDTEntryProp, SKEXT_KEY_PREFIX},
+    SkyError, TerminationReason,
 };
 
-use crate::system::{tasking::scheduler::Scheduler, RegisterState};
+use super::fail;
+use crate::system::{state::OSDTEntry, tasking::scheduler::Scheduler, RegisterState};
 
-pub fn new_entry(state: &mut RegisterState) -> ControlFlow<Option<TerminationReason>> {
+/// Whether `id` is one of `roots` or a descendant of one.
+pub fn is_within(
+    dt_index: &HashMap<u64, spin::Mutex<OSDTEntry>>,
+    roots: &[u64],
+    mut id: u64,
+) -> bool {
+    loop {
+        if roots.contains(&id) {
+            return true;
+        }
+        let Some(parent) = dt_index.get(&id).and_then(|v| v.lock().parent) else {
+            return false;
+        };
+        id = parent.into();
+    }
+}
+
+pub fn new_entry(
+    scheduler: &Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
     let sys_state = unsafe { &mut *crate::system::state::SYS_STATE.get() };
     let dt_index = sys_state.dt_index.as_ref().unwrap();
     let new = {
         let dt_index = dt_index.read();
         let Some(parent) = dt_index.get(&state.rsi) else {
-            return ControlFlow::Break(Some(TerminationReason::NotFound));
+            return fail(state, SkyError::NotFound);
         };
+        let roots = &scheduler.current_process().unwrap().caps.osdt_entries;
+        if !is_within(&dt_index, roots, state.rsi) {
+            return ControlFlow::Break(Some(TerminationReason::InsufficientPermissions));
+        }
         let v = crate::system::state::OSDTEntry {
             id: sys_state.dt_id_gen.as_ref().unwrap().lock().next(),
             parent: Some(state.rsi.into()),
@@ -41,13 +67,20 @@ pub fn get_info(
     };
     let dt_index = sys_state.dt_index.as_ref().unwrap().read();
     let Some(ent) = dt_index.get(&state.rsi) else {
-        return ControlFlow::Break(Some(TerminationReason::NotFound));
+        return fail(state, SkyError::NotFound);
     };
     let data = match info_type {
         OSDTEntryInfo::Parent => postcard::to_allocvec(&ent.lock().parent),
         OSDTEntryInfo::Children => postcard::to_allocvec(&ent.lock().children),
         OSDTEntryInfo::Properties => postcard::to_allocvec(&ent.lock().properties),
         OSDTEntryInfo::Property => {
+            if !scheduler
+                .current_process_mut()
+                .unwrap()
+                .fault_in(state.rcx, state.r8)
+            {
+                return ControlFlow::Break(Some(TerminationReason::MalformedAddress));
+            }
             let Ok(k) = core::str::from_utf8(unsafe {
                 core::slice::from_raw_parts(state.rcx as *const _, state.r8 as _)
             }) else {
@@ -56,29 +89,35 @@ pub fn get_info(
             postcard::to_allocvec(&ent.lock().properties.get(k))
         }
     }
-    .unwrap()
-    .leak();
+    .unwrap();
+    let buf = crate::system::allocator::alloc_pages(data.len());
+    buf.copy_from_slice(&data);
 
-    state.rax = scheduler
+    let Some(addr) = scheduler
         .current_process_mut()
         .unwrap()
-        .track_kernelside_alloc(data.as_ptr() as _, data.len() as _);
-    state.rdi = data.len() as _;
+        .track_kernelside_alloc(buf.as_ptr() as _, buf.len() as _)
+    else {
+        unsafe { crate::system::allocator::free_pages(buf) }
+        return fail(state, SkyError::OutOfMemory);
+    };
+    state.rax = addr;
+    state.rdi = buf.len() as _;
 
     ControlFlow::Continue(())
 }
 
 pub fn set_prop(
     scheduler: &mut Scheduler,
-    state: &RegisterState,
+    state: &mut RegisterState,
 ) -> ControlFlow<Option<TerminationReason>> {
     let addr = state.rdx;
     let size = state.rcx;
 
     if !scheduler
-        .current_process()
+        .current_process_mut()
         .unwrap()
-        .region_is_valid(addr, size)
+        .fault_in(addr, size)
     {
         return ControlFlow::Break(Some(TerminationReason::MalformedAddress));
     }
@@ -86,12 +125,20 @@ pub fn set_prop(
     let sys_state = unsafe { &mut *crate::system::state::SYS_STATE.get() };
     let dt_index = sys_state.dt_index.as_ref().unwrap().read();
     let Some(ent) = dt_index.get(&state.rsi) else {
-        return ControlFlow::Break(Some(TerminationReason::NotFound));
+        return fail(state, SkyError::NotFound);
     };
+    let roots = &scheduler.current_process().unwrap().caps.osdt_entries;
+    if !is_within(&dt_index, roots, state.rsi) {
+        return ControlFlow::Break(Some(TerminationReason::InsufficientPermissions));
+    }
     let data = unsafe { core::slice::from_raw_parts(addr as *const _, size as _) };
     let Ok(v) = postcard::from_bytes::<OSDTEntryProp>(data) else {
         return ControlFlow::Break(Some(TerminationReason::MalformedAddress));
     };
+    // Extension bookkeeping is managed by the kernel alone.
+    if v.0.starts_with(SKEXT_KEY_PREFIX) {
+        return ControlFlow::Break(Some(TerminationReason::InsufficientPermissions));
+    }
     ent.lock().properties.insert(v.0, v.1);
     drop(dt_index);
     crate::system::fkext::handle_change(scheduler, state.rsi.into());
@@ -3,15 +3,40 @@
 use core::ops::ControlFlow;
 
 use amd64::io::port::PortIO;
-use skykit::{syscall::AccessSize, TerminationReason};
+use skykit::{syscall::AccessSize, SkyError, TerminationReason};
 
-use crate::system::RegisterState;
+use super::fail;
+use crate::system::{tasking::scheduler::Scheduler, RegisterState};
 
-pub fn port_in(state: &mut RegisterState) -> ControlFlow<Option<TerminationReason>> {
+fn check_access(
+    scheduler: &Scheduler,
+    state: &RegisterState,
+) -> ControlFlow<Option<TerminationReason>, (u16, AccessSize)> {
     let port = state.rsi as u16;
     let Ok(access_size) = AccessSize::try_from(state.rdx) else {
         return ControlFlow::Break(Some(TerminationReason::MalformedArgument));
     };
+    let len = match access_size {
+        AccessSize::Byte => 1,
+        AccessSize::Word => 2,
+        AccessSize::DWord => 4,
+    };
+    if !scheduler
+        .current_process()
+        .unwrap()
+        .caps
+        .allows_ports(port, len)
+    {
+        return ControlFlow::Break(Some(TerminationReason::InsufficientPermissions));
+    }
+    ControlFlow::Continue((port, access_size))
+}
+
+pub fn port_in(
+    scheduler: &Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let (port, access_size) = check_access(scheduler, state)?;
     unsafe {
         state.rax = match access_size {
             AccessSize::Byte => u64::from(u8::read(port)),
@@ -22,11 +47,11 @@ pub fn port_in(state: &mut RegisterState) -> ControlFlow<Option<TerminationReaso
     ControlFlow::Continue(())
 }
 
-pub fn port_out(state: &RegisterState) -> ControlFlow<Option<TerminationReason>> {
-    let port = state.rsi as u16;
-    let Ok(access_size) = AccessSize::try_from(state.rdx) else {
-        return ControlFlow::Break(Some(TerminationReason::MalformedArgument));
-    };
+pub fn port_out(
+    scheduler: &Scheduler,
+    state: &RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let (port, access_size) = check_access(scheduler, state)?;
     unsafe {
         match access_size {
             AccessSize::Byte => u8::write(port, state.rcx as u8),
@@ -36,3 +61,25 @@ pub fn port_out(state: &RegisterState) -> ControlFlow<Option<TerminationReason>>
     }
     ControlFlow::Continue(())
 }
+
+pub fn grant_direct(
+    scheduler: &mut Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let (first, last) = (state.rsi as u16, state.rdx as u16);
+    if first > last {
+        return fail(state, SkyError::InvalidArgument);
+    }
+    let pid = scheduler.current_pid().unwrap();
+    let process = scheduler.current_process_mut().unwrap();
+    if !process.caps.allows_port_range(first, last) {
+        return fail(state, SkyError::InsufficientPermissions);
+    }
+
+    trace!("PID {pid}: Granting direct access to ports {first:#X}..={last:#X}");
+    process.allow_direct_ports(first, last);
+    // The bitmap changed under the process, possibly on several CPUs.
+    scheduler.reload_io_bitmap(pid);
+
+    ControlFlow::Continue(())
+}
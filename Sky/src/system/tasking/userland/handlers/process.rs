@@ -0,0 +1,61 @@
+// Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use core::ops::ControlFlow;
+
+use skykit::{msg::KernelMessage, process::SpawnRequest, SkyError, TerminationReason};
+
+use super::fail;
+use crate::system::{tasking::scheduler::Scheduler, RegisterState};
+
+pub fn spawn(
+    scheduler: &mut Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let (addr, size) = (state.rsi, state.rdx);
+    let pid = scheduler.current_pid().unwrap();
+
+    let process = scheduler.current_process_mut().unwrap();
+    if !process.fault_in(addr, size) {
+        return ControlFlow::Break(Some(TerminationReason::MalformedAddress));
+    }
+    let data = unsafe { core::slice::from_raw_parts(addr as *const _, size as _) };
+    let Ok(req) = postcard::from_bytes::<SpawnRequest>(data) else {
+        return ControlFlow::Break(Some(TerminationReason::MalformedBody));
+    };
+
+    trace!(
+        "PID {pid}: Spawning {} under <{:?}>",
+        req.identifier,
+        req.parent
+    );
+    match crate::system::fkext::spawn(scheduler, pid, &req) {
+        Ok((child, entry)) => {
+            state.rax = child;
+            state.rdx = entry;
+            ControlFlow::Continue(())
+        }
+        Err(e) => fail(state, e),
+    }
+}
+
+pub fn watch(
+    scheduler: &mut Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let target = state.rsi;
+    let pid = scheduler.current_pid().unwrap();
+    if target == pid {
+        return fail(state, SkyError::InvalidArgument);
+    }
+    if !scheduler.processes.contains_key(&target) {
+        return fail(state, SkyError::NotFound);
+    }
+
+    scheduler
+        .process_watchers
+        .entry(target)
+        .or_default()
+        .insert(pid);
+
+    ControlFlow::Continue(())
+}
@@ -0,0 +1,103 @@
+// Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use alloc::string::{String, ToString};
+use core::ops::ControlFlow;
+
+use skykit::{msg::KernelMessage, SkyError, TerminationReason};
+
+use super::fail;
+use crate::system::{tasking::scheduler::Scheduler, RegisterState};
+
+fn read_name(
+    scheduler: &mut Scheduler,
+    state: &RegisterState,
+) -> ControlFlow<Option<TerminationReason>, String> {
+    let addr = state.rsi;
+    let size = state.rdx;
+
+    if size == 0
+        || !scheduler
+            .current_process_mut()
+            .unwrap()
+            .fault_in(addr, size)
+    {
+        return ControlFlow::Break(Some(TerminationReason::MalformedAddress));
+    }
+
+    let s = unsafe { core::slice::from_raw_parts(addr as *const _, size as _) };
+    let Ok(s) = core::str::from_utf8(s) else {
+        return ControlFlow::Break(Some(TerminationReason::MalformedBody));
+    };
+
+    ControlFlow::Continue(s.to_string())
+}
+
+pub fn register(
+    scheduler: &mut Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let name = read_name(scheduler, state)?;
+    let pid = scheduler.current_pid().unwrap();
+
+    if scheduler.services.contains_key(&name) {
+        return fail(state, SkyError::AlreadyExists);
+    }
+
+    trace!("PID {pid}: Registering service {name}");
+    scheduler.services.insert(name.clone(), pid);
+    scheduler.notify_service_watchers(&name, &KernelMessage::ServiceRegistered(name.clone(), pid));
+
+    ControlFlow::Continue(())
+}
+
+pub fn unregister(
+    scheduler: &mut Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let name = read_name(scheduler, state)?;
+    let pid = scheduler.current_pid().unwrap();
+
+    if scheduler.services.get(&name) != Some(&pid) {
+        return fail(state, SkyError::NotFound);
+    }
+
+    trace!("PID {pid}: Unregistering service {name}");
+    scheduler.services.remove(&name);
+    scheduler.notify_service_watchers(&name, &KernelMessage::ServiceUnregistered(name.clone()));
+
+    ControlFlow::Continue(())
+}
+
+pub fn lookup(
+    scheduler: &mut Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let name = read_name(scheduler, state)?;
+    let Some(pid) = scheduler.services.get(&name).copied() else {
+        return fail(state, SkyError::NotFound);
+    };
+    state.rax = pid;
+
+    ControlFlow::Continue(())
+}
+
+pub fn watch(
+    scheduler: &mut Scheduler,
+    state: &RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let name = read_name(scheduler, state)?;
+    let pid = scheduler.current_pid().unwrap();
+
+    scheduler
+        .service_watchers
+        .entry(name.clone())
+        .or_default()
+        .insert(pid);
+
+    // Let the watcher know right away if the name is already taken.
+    if let Some(owner) = scheduler.services.get(&name).copied() {
+        let _ = scheduler.send_kernel_msg(pid, &KernelMessage::ServiceRegistered(name, owner));
+    }
+
+    ControlFlow::Continue(())
+}
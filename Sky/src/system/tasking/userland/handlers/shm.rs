@@ -0,0 +1,172 @@
+// Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use core::ops::ControlFlow;
+
+use hashbrown::HashMap;
+use skykit::{SkyError, TerminationReason};
+
+use super::fail;
+use crate::system::{
+    tasking::{scheduler::Scheduler, vma::Area, AllocationType, SharedMemory},
+    RegisterState,
+};
+
+pub fn create(
+    scheduler: &mut Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let size = state.rsi;
+    if size == 0 {
+        return fail(state, SkyError::InvalidArgument);
+    }
+
+    let page_count = size.div_ceil(0x1000);
+    let Some(phys) = (unsafe {
+        (*crate::system::state::SYS_STATE.get())
+            .pmm
+            .as_ref()
+            .unwrap()
+            .lock()
+            .alloc(page_count)
+    }) else {
+        return fail(state, SkyError::OutOfMemory);
+    };
+    let phys = phys as u64;
+    unsafe {
+        core::ptr::write_bytes(
+            (phys + amd64::paging::PHYS_VIRT_OFFSET) as *mut u8,
+            0,
+            (page_count * 0x1000) as _,
+        );
+    }
+
+    let owner = scheduler.current_pid().unwrap();
+    let id = scheduler.shm_id_gen.next();
+    trace!("PID {owner}: Created shared memory {id} ({page_count} pages, {size} bytes)");
+    scheduler.shared_mem.insert(
+        id,
+        SharedMemory {
+            owner,
+            phys,
+            size,
+            grants: HashMap::new(),
+        },
+    );
+
+    state.rax = id;
+    ControlFlow::Continue(())
+}
+
+pub fn grant(
+    scheduler: &mut Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let (id, target, writable) = (state.rsi, state.rdx, state.rcx != 0);
+    let pid = scheduler.current_pid().unwrap();
+
+    if target == pid || !scheduler.processes.contains_key(&target) {
+        return fail(state, SkyError::NotFound);
+    }
+    let Some(shm) = scheduler.shared_mem.get_mut(&id) else {
+        return fail(state, SkyError::NotFound);
+    };
+    if shm.owner != pid {
+        return fail(state, SkyError::InsufficientPermissions);
+    }
+
+    // Changing the rights of an existing grant needs the mapping to be redone.
+    if shm
+        .grants
+        .insert(target, writable)
+        .is_some_and(|v| v != writable)
+    {
+        let process = scheduler.processes.get_mut(&target).unwrap();
+        if let Some(addr) = process.shm_mapping(id) {
+            process.retype_alloc(addr, AllocationType::Shared { id, writable });
+        }
+    }
+
+    ControlFlow::Continue(())
+}
+
+pub fn revoke(
+    scheduler: &mut Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let (id, target) = (state.rsi, state.rdx);
+    let pid = scheduler.current_pid().unwrap();
+
+    let Some(shm) = scheduler.shared_mem.get_mut(&id) else {
+        return fail(state, SkyError::NotFound);
+    };
+    if shm.owner != pid {
+        return fail(state, SkyError::InsufficientPermissions);
+    }
+    if shm.grants.remove(&target).is_none() {
+        return fail(state, SkyError::NotFound);
+    }
+
+    if let Some(process) = scheduler.processes.get_mut(&target) {
+        if let Some(addr) = process.shm_mapping(id) {
+            process.free_alloc(addr);
+        }
+    }
+
+    ControlFlow::Continue(())
+}
+
+pub fn map(
+    scheduler: &mut Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let id = state.rsi;
+    let pid = scheduler.current_pid().unwrap();
+
+    let Some(shm) = scheduler.shared_mem.get(&id) else {
+        return fail(state, SkyError::NotFound);
+    };
+    let writable = if shm.owner == pid {
+        true
+    } else if let Some(&v) = shm.grants.get(&pid) {
+        v
+    } else {
+        return fail(state, SkyError::InsufficientPermissions);
+    };
+    let (phys, size) = (shm.phys, shm.size);
+
+    let process = scheduler.current_process_mut().unwrap();
+    if process.shm_mapping(id).is_some() {
+        return fail(state, SkyError::AlreadyExists);
+    }
+    let Some(addr) = process.track_alloc(
+        Area::Heap,
+        phys,
+        size,
+        AllocationType::Shared { id, writable },
+    ) else {
+        return fail(state, SkyError::OutOfMemory);
+    };
+
+    state.rax = addr;
+    state.rdx = size;
+    ControlFlow::Continue(())
+}
+
+pub fn unmap(
+    scheduler: &mut Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let id = state.rsi;
+
+    if !scheduler.shared_mem.contains_key(&id) {
+        return fail(state, SkyError::NotFound);
+    }
+
+    let process = scheduler.current_process_mut().unwrap();
+    let Some(addr) = process.shm_mapping(id) else {
+        return fail(state, SkyError::NotFound);
+    };
+    process.free_alloc(addr);
+
+    ControlFlow::Continue(())
+}
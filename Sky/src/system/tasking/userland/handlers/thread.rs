@@ -0,0 +1,164 @@
+// Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use core::ops::ControlFlow;
+
+use amd64::msr::{fs_base::FsBase, kernel_gs_base::KernelGsBase, ModelSpecificReg};
+use skykit::{syscall::TlsRegister, SkyError, TerminationReason};
+
+use super::fail;
+use crate::system::{
+    tasking::{scheduler::Scheduler, vma::Area, ThreadState, STACK_SIZE},
+    RegisterState,
+};
+
+pub fn spawn(
+    scheduler: &mut Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let (rip, arg) = (state.rsi, state.rdx);
+
+    let process = scheduler.current_process_mut().unwrap();
+    if !process.region_is_valid(rip, 1) {
+        return ControlFlow::Break(Some(TerminationReason::MalformedAddress));
+    }
+    let Some((stack_addr, _)) = process.allocate(Area::Stack, STACK_SIZE) else {
+        return fail(state, SkyError::OutOfMemory);
+    };
+
+    let tid = scheduler.tid_gen.next();
+    let priority = scheduler.current_thread().unwrap().priority;
+    let process = scheduler.current_process_mut().unwrap();
+    trace!("PID {}: Spawning thread {tid} at {rip:#X}", process.id);
+    let mut thread = process.new_thread(tid, rip, stack_addr);
+    if !process.setup_tls(&mut thread) {
+        process.thread_ids.remove(&tid);
+        process.free_alloc(stack_addr);
+        scheduler.tid_gen.free(tid);
+        return fail(state, SkyError::OutOfMemory);
+    }
+    thread.regs.rdi = arg;
+    thread.priority = priority;
+    scheduler.add_thread(thread);
+
+    state.rax = tid;
+    ControlFlow::Continue(())
+}
+
+pub fn set_tls_base(
+    scheduler: &mut Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let Ok(reg) = TlsRegister::try_from(state.rsi) else {
+        return ControlFlow::Break(Some(TerminationReason::MalformedArgument));
+    };
+    let addr = state.rdx;
+    if addr != 0
+        && !scheduler
+            .current_process()
+            .unwrap()
+            .region_is_valid(addr, 8)
+    {
+        return ControlFlow::Break(Some(TerminationReason::MalformedAddress));
+    }
+
+    // This thread resumes without going through the scheduler, so load the MSR right away.
+    let thread = scheduler.current_thread_mut().unwrap();
+    unsafe {
+        match reg {
+            TlsRegister::Fs => {
+                thread.fs_base = addr as _;
+                FsBase::new().with_base(addr).write();
+            }
+            TlsRegister::Gs => {
+                thread.gs_base = addr as _;
+                // Swapped into `GS` on the way back to user-land.
+                KernelGsBase::new().with_base(addr).write();
+            }
+        }
+    }
+
+    ControlFlow::Continue(())
+}
+
+pub fn set_priority(
+    scheduler: &mut Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let (tid, priority) = (state.rsi, state.rdx);
+    let tid = if tid == 0 {
+        scheduler.current_tid().unwrap()
+    } else {
+        tid
+    };
+    let Ok(priority) = u8::try_from(priority) else {
+        return fail(state, SkyError::InvalidArgument);
+    };
+    if priority > skykit::priority::MAX {
+        return fail(state, SkyError::InvalidArgument);
+    }
+
+    let process = scheduler.current_process().unwrap();
+    if !process.thread_ids.contains(&tid) {
+        return fail(state, SkyError::NotFound);
+    }
+    if skykit::priority::is_realtime(priority) && process.caps.irqs.is_empty() {
+        return fail(state, SkyError::InsufficientPermissions);
+    }
+
+    trace!(
+        "PID {}: Thread {tid} priority set to {priority}",
+        process.id
+    );
+    scheduler.set_priority(tid, priority);
+
+    // Lowering its own priority may let another thread run first.
+    if scheduler.should_preempt() {
+        return ControlFlow::Break(None);
+    }
+    ControlFlow::Continue(())
+}
+
+pub fn join(
+    scheduler: &mut Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let tid = state.rsi;
+    if Some(tid) == scheduler.current_tid() {
+        return fail(state, SkyError::InvalidArgument);
+    }
+
+    let process = scheduler.current_process_mut().unwrap();
+    if let Some(value) = process.exited_threads.remove(&tid) {
+        scheduler.tid_gen.free(tid);
+        state.rax = value;
+        return ControlFlow::Continue(());
+    }
+    if !process.thread_ids.contains(&tid) {
+        return fail(state, SkyError::NotFound);
+    }
+
+    let thread = scheduler.current_thread_mut().unwrap();
+    thread.state = ThreadState::Suspended;
+    thread.join_wait = Some(tid);
+    ControlFlow::Break(None)
+}
+
+pub fn detach(
+    scheduler: &mut Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let tid = state.rsi;
+
+    let process = scheduler.current_process_mut().unwrap();
+    if process.exited_threads.remove(&tid).is_some() {
+        scheduler.tid_gen.free(tid);
+        return ControlFlow::Continue(());
+    }
+    if !process.thread_ids.contains(&tid) {
+        return fail(state, SkyError::NotFound);
+    }
+
+    trace!("PID {}: Detaching thread {tid}", process.id);
+    process.detached_threads.insert(tid);
+    ControlFlow::Continue(())
+}
@@ -0,0 +1,77 @@
+// Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use core::ops::ControlFlow;
+
+use skykit::{SkyError, TerminationReason};
+
+use super::fail;
+use crate::system::{
+    tasking::{scheduler::Scheduler, ThreadState, UserTimer},
+    RegisterState,
+};
+
+/// Shorter periods would keep the CPU busy with nothing but timer interrupts.
+const MIN_PERIOD_NS: u64 = 1_000_000;
+
+pub fn clock(state: &mut RegisterState) -> ControlFlow<Option<TerminationReason>> {
+    state.rax = crate::timer::monotonic_ns();
+    ControlFlow::Continue(())
+}
+
+pub fn sleep_until(
+    scheduler: &mut Scheduler,
+    state: &RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let deadline = state.rsi;
+    if deadline <= crate::timer::monotonic_ns() {
+        return ControlFlow::Continue(());
+    }
+
+    let thread = scheduler.current_thread_mut().unwrap();
+    thread.state = ThreadState::Suspended;
+    thread.sleep_until = Some(deadline);
+    scheduler.deadline_added();
+    ControlFlow::Break(None)
+}
+
+pub fn create_timer(
+    scheduler: &mut Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let (deadline, period) = (state.rsi, state.rdx);
+    if period != 0 && period < MIN_PERIOD_NS {
+        return fail(state, SkyError::InvalidArgument);
+    }
+
+    let owner = scheduler.current_pid().unwrap();
+    let id = scheduler.timer_id_gen.next();
+    trace!("PID {owner}: Created timer {id} (deadline {deadline} ns, period {period} ns)");
+    scheduler.timers.insert(
+        id,
+        UserTimer {
+            owner,
+            deadline,
+            period: (period != 0).then_some(period),
+        },
+    );
+    scheduler.deadline_added();
+
+    state.rax = id;
+    ControlFlow::Continue(())
+}
+
+pub fn cancel_timer(
+    scheduler: &mut Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let id = state.rsi;
+    let pid = scheduler.current_pid().unwrap();
+
+    if scheduler.timers.get(&id).is_none_or(|v| v.owner != pid) {
+        return fail(state, SkyError::NotFound);
+    }
+    scheduler.timers.remove(&id);
+    scheduler.timer_id_gen.free(id);
+
+    ControlFlow::Continue(())
+}
@@ -2,54 +2,201 @@
 
 use core::ops::ControlFlow;
 
+use amd64::msr::{
+    efer::ExtendedFeatureEnableReg, lstar::LongSysCallTargetAddr, sfmask::SysCallFlagMask,
+    star::SysCallTargetAddr, ModelSpecificReg,
+};
 use skykit::{syscall::SystemCall, TerminationReason};
 
-use crate::system::{gdt::PrivilegeLevel, RegisterState};
+use crate::system::{
+    gdt::{PrivilegeLevel, SegmentSelector},
+    smp::Cpu,
+    RegisterState,
+};
 
 pub mod handlers;
 pub mod page_table;
 
+/// Builds the same frame as the `int 249` gate so both paths share [`dispatch`].
+/// Returns with `SYSRET` if the caller keeps running, or `IRETQ` if another thread was scheduled.
+#[naked]
+unsafe extern "sysv64" fn syscall_entry() {
+    core::arch::naked_asm!(
+        "swapgs",
+        "mov gs:[{user_rsp}], rsp",
+        "mov rsp, gs:[{kern_rsp}]",
+        "push {user_ss}",
+        "push qword ptr gs:[{user_rsp}]",
+        "push r11",
+        "push {user_cs}",
+        "push rcx",
+        "push 0",
+        "push 249",
+        "push rax",
+        "push rbx",
+        // SYSCALL clobbers RCX, so the fourth argument is passed in R10 instead.
+        "push r10",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rbp",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push r11",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov rdi, rsp",
+        "call {handler}",
+        "test al, al",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rbp",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+        "lea rsp, [rsp + 16]",
+        "jz 2f",
+        "pop rcx",
+        "lea rsp, [rsp + 8]",
+        "pop r11",
+        "pop rsp",
+        "swapgs",
+        "sysretq",
+        "2:",
+        "test byte ptr [rsp + 8], 3",
+        "jz 3f",
+        "swapgs",
+        "3:",
+        "iretq",
+        user_rsp = const core::mem::offset_of!(Cpu, user_rsp),
+        kern_rsp = const core::mem::offset_of!(Cpu, kern_rsp),
+        user_ss = const SegmentSelector::new(3, PrivilegeLevel::User).0,
+        user_cs = const SegmentSelector::new(4, PrivilegeLevel::User).0,
+        handler = sym fast_syscall_handler,
+    )
+}
+
+unsafe extern "sysv64" fn fast_syscall_handler(state: &mut RegisterState) -> bool {
+    // SYSRET with a non-canonical RIP faults in kernel mode.
+    dispatch(state) && state.rip < 0x8000_0000_0000
+}
+
 unsafe extern "sysv64" fn syscall_handler(state: &mut RegisterState) {
-    let sys_state = &mut *crate::system::state::SYS_STATE.get();
-    let mut scheduler = sys_state.scheduler.as_ref().unwrap().lock();
+    dispatch(state);
+}
+
+/// Returns whether the calling thread is resumed as-is.
+unsafe fn dispatch(state: &mut RegisterState) -> bool {
+    let mut scheduler = crate::system::smp::lock_scheduler();
 
+    // Another CPU tore the process down while this thread was on its way in.
+    if scheduler.current_thread().unwrap().state.is_dying() {
+        scheduler.schedule(state);
+        return false;
+    }
+
+    state.r9 = 0;
     let flow = 'flow: {
         let Ok(v) = SystemCall::try_from(state.rdi) else {
             break 'flow ControlFlow::Break(Some(TerminationReason::MalformedArgument));
         };
 
         match v {
-            SystemCall::KPrint => handlers::kprint(&scheduler, state),
+            SystemCall::KPrint => handlers::kprint(&mut scheduler, state),
             SystemCall::MsgRecv => handlers::msg::recv(&mut scheduler, state),
             SystemCall::MsgSend => handlers::msg::send(&mut scheduler, state),
-            SystemCall::Quit => scheduler.thread_teardown(),
+            SystemCall::Quit => {
+                scheduler.process_teardown(state.rsi, None);
+                ControlFlow::Break(None)
+            }
             SystemCall::Yield => ControlFlow::Break(None),
-            SystemCall::PortIn => handlers::port::port_in(state),
-            SystemCall::PortOut => handlers::port::port_out(state),
+            SystemCall::PortIn => handlers::port::port_in(&scheduler, state),
+            SystemCall::PortOut => handlers::port::port_out(&scheduler, state),
             SystemCall::RegisterIRQ => scheduler.register_irq(state),
             SystemCall::Allocate => handlers::alloc::alloc(&mut scheduler, state),
             SystemCall::Free => handlers::alloc::free(&mut scheduler, state),
             SystemCall::MsgAck => handlers::msg::ack(&mut scheduler, state),
-            SystemCall::NewOSDTEntry => handlers::os_dt_entry::new_entry(state),
+            SystemCall::NewOSDTEntry => handlers::os_dt_entry::new_entry(&scheduler, state),
             SystemCall::GetOSDTEntryInfo => handlers::os_dt_entry::get_info(&mut scheduler, state),
             SystemCall::SetOSDTEntryProp => handlers::os_dt_entry::set_prop(&mut scheduler, state),
+            SystemCall::MsgRecvFiltered => handlers::msg::recv_filtered(&mut scheduler, state),
+            SystemCall::RegisterService => handlers::service::register(&mut scheduler, state),
+            SystemCall::UnregisterService => handlers::service::unregister(&mut scheduler, state),
+            SystemCall::LookupService => handlers::service::lookup(&mut scheduler, state),
+            SystemCall::WatchService => handlers::service::watch(&mut scheduler, state),
+            SystemCall::ShmCreate => handlers::shm::create(&mut scheduler, state),
+            SystemCall::ShmGrant => handlers::shm::grant(&mut scheduler, state),
+            SystemCall::ShmRevoke => handlers::shm::revoke(&mut scheduler, state),
+            SystemCall::ShmMap => handlers::shm::map(&mut scheduler, state),
+            SystemCall::ShmUnmap => handlers::shm::unmap(&mut scheduler, state),
+            SystemCall::GrantCapabilities => handlers::caps::grant(&mut scheduler, state),
+            SystemCall::PortGrantDirect => handlers::port::grant_direct(&mut scheduler, state),
+            SystemCall::MmioMap => handlers::mmio::map(&mut scheduler, state),
+            SystemCall::MmioUnmap => handlers::mmio::unmap(&mut scheduler, state),
+            SystemCall::DmaAllocate => handlers::alloc::dma_alloc(&mut scheduler, state),
+            SystemCall::ThreadSpawn => handlers::thread::spawn(&mut scheduler, state),
+            SystemCall::ThreadExit => scheduler.thread_teardown(state.rsi),
+            SystemCall::ThreadJoin => handlers::thread::join(&mut scheduler, state),
+            SystemCall::ThreadDetach => handlers::thread::detach(&mut scheduler, state),
+            SystemCall::FutexWait => handlers::futex::wait(&mut scheduler, state),
+            SystemCall::FutexWake => handlers::futex::wake(&mut scheduler, state),
+            SystemCall::ClockMonotonic => handlers::time::clock(state),
+            SystemCall::SleepUntil => handlers::time::sleep_until(&mut scheduler, state),
+            SystemCall::TimerCreate => handlers::time::create_timer(&mut scheduler, state),
+            SystemCall::TimerCancel => handlers::time::cancel_timer(&mut scheduler, state),
+            SystemCall::SpawnExtension => handlers::process::spawn(&mut scheduler, state),
+            SystemCall::WatchProcess => handlers::process::watch(&mut scheduler, state),
+            SystemCall::SetTlsBase => handlers::thread::set_tls_base(&mut scheduler, state),
+            SystemCall::SetPriority => handlers::thread::set_priority(&mut scheduler, state),
         }
     };
 
     let ControlFlow::Break(reason) = flow else {
-        return;
+        return true;
     };
 
     if let Some(reason) = reason {
         debug!(
             "PID {} performed illegal action (<{reason:?}>). Killing it, good riddance.",
-            scheduler.current_pid.unwrap()
+            scheduler.current_pid().unwrap()
         );
-        scheduler.process_teardown();
+        scheduler.process_teardown(u64::MAX, Some(reason));
     }
     scheduler.schedule(state);
+    false
 }
 
 pub fn setup() {
     crate::interrupts::idt::set_handler(249, 1, PrivilegeLevel::User, syscall_handler, false, true);
+    init_cpu();
+}
+
+/// Enables `SYSCALL` on the calling CPU.
+pub fn init_cpu() {
+    unsafe {
+        ExtendedFeatureEnableReg::read()
+            .with_syscall_ext(true)
+            .write();
+        SysCallTargetAddr::new()
+            .with_syscall_cs_ss(SegmentSelector::new(1, PrivilegeLevel::Supervisor).0)
+            .with_sysret_cs_ss(SegmentSelector::new(2, PrivilegeLevel::User).0)
+            .write();
+        LongSysCallTargetAddr::new()
+            .with_target_rip(syscall_entry as *const () as u64)
+            .write();
+        // TF, IF, DF, IOPL, NT and AC
+        SysCallFlagMask::new().with_mask(0x4_7700).write();
+    }
 }
@@ -1,56 +1,72 @@
 // Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
 
-use alloc::boxed::Box;
+use alloc::{boxed::Box, vec::Vec};
+use core::cell::RefCell;
 
-use amd64::paging::{PageTable, PageTableFlags, PAGE_SIZE};
-
-use crate::system::tasking::AllocationType;
+use amd64::paging::{PageTable, PageTableFlags, PHYS_VIRT_OFFSET};
 
 #[derive(Debug)]
 #[repr(C)]
-pub struct UserPML4(PageTable<{ amd64::paging::PHYS_VIRT_OFFSET }>, u64);
+pub struct UserPML4 {
+    pml4: PageTable<PHYS_VIRT_OFFSET>,
+    /// Physical addresses of the lower level tables, which go with the PML4.
+    tables: RefCell<Vec<u64>>,
+}
+
+impl Default for UserPML4 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl UserPML4 {
     #[inline]
-    pub const fn new(pid: u64) -> Self {
-        Self(amd64::paging::PageTable::new(), pid)
+    pub const fn new() -> Self {
+        Self {
+            pml4: PageTable::new(),
+            tables: RefCell::new(Vec::new()),
+        }
     }
 
-    fn alloc_entry(pid: u64) -> u64 {
-        let sys_state = unsafe { &mut *crate::system::state::SYS_STATE.get() };
-        let phys = Box::leak(Box::new(PageTable::<0>::new())) as *mut _ as u64
-            - amd64::paging::PHYS_VIRT_OFFSET;
-
-        let scheduler = sys_state.scheduler.as_mut().unwrap().get_mut();
-        scheduler.processes.get_mut(&pid).unwrap().track_alloc(
-            phys + skykit::USER_VIRT_OFFSET,
-            PAGE_SIZE,
-            AllocationType::Kernel,
-        );
-
+    fn alloc_entry(tables: &RefCell<Vec<u64>>) -> u64 {
+        let phys = Box::leak(Box::new(PageTable::<0>::new())) as *mut _ as u64 - PHYS_VIRT_OFFSET;
+        tables.borrow_mut().push(phys);
         phys
     }
 
     #[inline]
     pub unsafe fn set_cr3(&mut self) {
-        self.0.set_cr3();
+        self.pml4.set_cr3();
     }
 
     #[inline]
     pub unsafe fn map(&mut self, virt: u64, phys: u64, count: u64, flags: PageTableFlags) {
-        let pid = self.1;
-        self.0
-            .map(&move || Self::alloc_entry(pid), virt, phys, count, flags);
+        let tables = &self.tables;
+        self.pml4
+            .map(&|| Self::alloc_entry(tables), virt, phys, count, flags);
     }
 
     #[inline]
     pub unsafe fn unmap(&mut self, virt: u64, count: u64) {
-        self.0.unmap(virt, count);
+        self.pml4.unmap(virt, count);
+    }
+
+    #[inline]
+    pub unsafe fn virt_to_phys(&mut self, virt: u64) -> Option<u64> {
+        self.pml4.virt_to_phys(virt).map(|(v, _)| v)
     }
 
     #[inline]
     pub unsafe fn map_higher_half(&mut self) {
-        let pid = self.1;
-        self.0.map_higher_half(&move || Self::alloc_entry(pid));
+        let tables = &self.tables;
+        self.pml4.map_higher_half(&|| Self::alloc_entry(tables));
+    }
+}
+
+impl Drop for UserPML4 {
+    fn drop(&mut self) {
+        for &phys in self.tables.get_mut().iter() {
+            drop(unsafe { Box::from_raw((phys + PHYS_VIRT_OFFSET) as *mut PageTable<0>) });
+        }
     }
 }
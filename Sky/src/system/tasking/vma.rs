@@ -0,0 +1,106 @@
+// Copyright (c) ChefKiss 2021-2024. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use alloc::collections::BTreeMap;
+
+use amd64::{cpuid::CPUIdentification, paging::PAGE_SIZE};
+
+/// Part of the user address space a range is placed in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Area {
+    Image,
+    Heap,
+    Stack,
+}
+
+impl Area {
+    const COUNT: usize = 3;
+
+    /// First and last address of the window the area lives in.
+    const fn window(self) -> (u64, u64) {
+        match self {
+            Self::Image => (0x0000_1000_0000_0000, 0x0000_2000_0000_0000),
+            Self::Heap => (0x0000_2000_0000_0000, 0x0000_6000_0000_0000),
+            Self::Stack => (0x0000_6000_0000_0000, 0x0000_7FFF_0000_0000),
+        }
+    }
+}
+
+/// How far past the start of its window an area may begin.
+const RANDOM_RANGE: u64 = 0x100_0000_0000;
+
+/// Random page aligned offset below [`RANDOM_RANGE`]. RDRAND is used where present, a scrambled
+/// TSC reading otherwise.
+fn random_offset() -> u64 {
+    let mut v = 0;
+    let have_rdrand = CPUIdentification::new().features.rdrand()
+        && (0..10).any(|_| unsafe { core::arch::x86_64::_rdrand64_step(&mut v) } == 1);
+    if !have_rdrand {
+        // SplitMix64 finaliser
+        v = unsafe { core::arch::x86_64::_rdtsc() };
+        v = (v ^ (v >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        v = (v ^ (v >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        v ^= v >> 31;
+    }
+    (v % (RANDOM_RANGE / PAGE_SIZE)) * PAGE_SIZE
+}
+
+/// Reserved ranges of a process's address space. Every area starts at a random address and
+/// ranges are placed first fit from there, each with an unmapped guard page below it.
+#[derive(Debug)]
+pub struct VmaTree {
+    /// End of each range, by start.
+    ranges: BTreeMap<u64, u64>,
+    bases: [u64; Area::COUNT],
+}
+
+impl Default for VmaTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VmaTree {
+    pub fn new() -> Self {
+        Self {
+            ranges: BTreeMap::new(),
+            bases: [Area::Image, Area::Heap, Area::Stack].map(|v| v.window().0 + random_offset()),
+        }
+    }
+
+    /// Reserves `size` bytes, rounded up to pages, in `area`. Returns the start of the range.
+    pub fn reserve(&mut self, area: Area, size: u64) -> Option<u64> {
+        let size = size.checked_next_multiple_of(PAGE_SIZE)?;
+        if size == 0 {
+            return None;
+        }
+        let base = self.bases[area as usize];
+        let limit = area.window().1;
+
+        let mut addr = base + PAGE_SIZE;
+        for (&start, &end) in self.ranges.range(base..limit) {
+            if addr.checked_add(size)? <= start {
+                break;
+            }
+            addr = end + PAGE_SIZE;
+        }
+        if addr.checked_add(size)? > limit {
+            return None;
+        }
+
+        self.ranges.insert(addr, addr + size);
+        Some(addr)
+    }
+
+    /// Start of the range holding `addr`, if any.
+    pub fn find(&self, addr: u64) -> Option<u64> {
+        self.ranges
+            .range(..=addr)
+            .next_back()
+            .and_then(|(&start, &end)| (addr < end).then_some(start))
+    }
+
+    /// Drops the range starting at `addr`, returning its size.
+    pub fn release(&mut self, addr: u64) -> Option<u64> {
+        self.ranges.remove(&addr).map(|end| end - addr)
+    }
+}
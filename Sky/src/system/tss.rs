@@ -10,9 +10,13 @@ pub struct TaskSegmentSelector {
     ____: u64,
     _____: u16,
     pub io_bitmap_offset: u16,
-    pub io_bitmap: [u8; 8193],
+    /// One bit per port, set to deny user access.
+    /// The trailing byte must stay all ones.
+    pub io_bitmap: [u8; IO_BITMAP_SIZE + 1],
 }
 
+pub const IO_BITMAP_SIZE: usize = 0x2000;
+
 impl TaskSegmentSelector {
     #[inline]
     pub const fn new(kern_rsp: u64) -> Self {
@@ -23,8 +27,16 @@ impl TaskSegmentSelector {
             interrupt_stack_table: [kern_rsp; 7],
             ____: 0,
             _____: 0,
-            io_bitmap_offset: 112,
-            io_bitmap: [0; 8193],
+            io_bitmap_offset: core::mem::offset_of!(Self, io_bitmap) as u16,
+            io_bitmap: [0xFF; IO_BITMAP_SIZE + 1],
+        }
+    }
+
+    /// Loads `bitmap`, or denies every port if there is none.
+    pub fn set_io_bitmap(&mut self, bitmap: Option<&[u8]>) {
+        match bitmap {
+            Some(v) => self.io_bitmap[..IO_BITMAP_SIZE].copy_from_slice(v),
+            None => self.io_bitmap[..IO_BITMAP_SIZE].fill(0xFF),
         }
     }
 }
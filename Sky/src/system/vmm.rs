@@ -43,14 +43,7 @@ impl PageTableLvl4 {
     }
 
     pub unsafe fn init(&mut self) {
-        // Fix performance by utilising the PAT mechanism
-        PageAttributeTable::new()
-            .with_pat0(PATEntry::WriteBack)
-            .with_pat1(PATEntry::WriteThrough)
-            .with_pat2(PATEntry::WriteCombining)
-            .with_pat3(PATEntry::WriteProtected)
-            .write();
-
+        init_pat();
         self.map_higher_half();
         self.set_cr3();
     }
@@ -59,3 +52,15 @@ impl PageTableLvl4 {
         self.map(virt, phys, count, flags.with_pat_entry(1));
     }
 }
+
+/// Every CPU needs the same PAT for the mappings to mean the same thing.
+pub unsafe fn init_pat() {
+    // Fix performance by utilising the PAT mechanism
+    PageAttributeTable::new()
+        .with_pat0(PATEntry::WriteBack)
+        .with_pat1(PATEntry::WriteThrough)
+        .with_pat2(PATEntry::WriteCombining)
+        .with_pat3(PATEntry::WriteProtected)
+        .with_pat4(PATEntry::Uncacheable)
+        .write();
+}
@@ -16,6 +16,12 @@ impl Hpet {
         hpet.set_config(GeneralConfiguration::new().with_main_cnt_enable(true));
         Self { inner: hpet, clk }
     }
+
+    /// Nanoseconds since the counter was started.
+    pub fn now_ns(&self) -> u64 {
+        // The period is in femtoseconds.
+        (u128::from(self.inner.counter_value()) * u128::from(self.clk) / 1_000_000) as u64
+    }
 }
 
 impl super::Timer for Hpet {
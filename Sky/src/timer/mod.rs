@@ -6,3 +6,10 @@ pub mod pit;
 pub trait Timer {
     fn sleep(&self, ms: u64);
 }
+
+/// Monotonic nanosecond clock, backed by the HPET.
+pub fn monotonic_ns() -> u64 {
+    unsafe { (*crate::system::state::SYS_STATE.get()).hpet.as_ref() }
+        .unwrap()
+        .now_ns()
+}
@@ -18,32 +18,48 @@ impl MemoryManager {
         }
     }
 
+    /// Keeps the pages of `ent`, a base and a length in bytes, from being reported as reclaimable.
     pub fn allocate(&mut self, ent: (u64, u64)) {
-        self.entries.push(MemoryData::new(ent.0, ent.1));
+        let base = ent.0 & !(PAGE_SIZE - 1);
+        let end = (ent.0 + ent.1).next_multiple_of(PAGE_SIZE);
+        let idx = self.entries.partition_point(|v| v.base < base);
+        self.entries.insert(idx, MemoryData::new(base, end - base));
     }
 
-    pub fn mem_type_from_desc(&self, desc: &MemoryDescriptor) -> Option<MemoryEntry> {
+    /// Pushes the entries describing `desc` to `out`. Loader memory is split around the
+    /// allocated ranges, and the rest is left for the kernel to reclaim.
+    /// Runs after exiting boot services, so `out` must have room for them.
+    pub fn push_entries(&self, desc: &MemoryDescriptor, out: &mut Vec<MemoryEntry>) {
         let data = MemoryData::new(desc.phys_start, desc.page_count * PAGE_SIZE);
 
         match desc.ty {
-            MemoryType::CONVENTIONAL => Some(MemoryEntry::Usable(data)),
+            MemoryType::CONVENTIONAL => out.push(MemoryEntry::Usable(data)),
             MemoryType::LOADER_CODE | MemoryType::LOADER_DATA => {
-                let Some(reserved) = self.entries.iter().find(|v| data.base <= v.base + v.length)
-                else {
-                    return Some(MemoryEntry::BootLoaderReclaimable(data));
-                };
-
-                if data.base + data.length > reserved.base + reserved.length {
-                    return Some(MemoryEntry::BootLoaderReclaimable(MemoryData::new(
-                        data.base + reserved.length,
-                        data.length - reserved.length,
-                    )));
+                let end = data.base + data.length;
+                let mut base = data.base;
+                for v in self
+                    .entries
+                    .iter()
+                    .filter(|v| v.base < end && v.base + v.length > data.base)
+                {
+                    if v.base > base {
+                        out.push(MemoryEntry::BootLoaderReclaimable(MemoryData::new(
+                            base,
+                            v.base - base,
+                        )));
+                    }
+                    base = base.max(v.base + v.length);
                 }
 
-                None
+                if base < end {
+                    out.push(MemoryEntry::BootLoaderReclaimable(MemoryData::new(
+                        base,
+                        end - base,
+                    )));
+                }
             }
-            MemoryType::ACPI_RECLAIM => Some(MemoryEntry::ACPIReclaimable(data)),
-            _ => None,
+            MemoryType::ACPI_RECLAIM => out.push(MemoryEntry::ACPIReclaimable(data)),
+            _ => {}
         }
     }
 }
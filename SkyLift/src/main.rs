@@ -67,9 +67,7 @@ extern "efiapi" fn efi_main(image: Handle, st: *const core::ffi::c_void) -> Stat
     let mut memory_map_entries = Vec::with_capacity(memory_map_entry_count);
 
     for v in unsafe { uefi::boot::exit_boot_services(MemoryType::LOADER_DATA).entries() } {
-        if let Some(v) = mem_mgr.mem_type_from_desc(v) {
-            memory_map_entries.push(v);
-        }
+        mem_mgr.push_entries(v, &mut memory_map_entries);
     }
     boot_info.memory_map = helpers::phys_to_kern_slice_ref(memory_map_entries.leak());
 